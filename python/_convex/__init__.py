@@ -1,16 +1,27 @@
 __all__ = [
     "PyQuerySubscription",
     "PyQuerySetSubscription",
+    "PyAuthState",
     "PyConvexClient",
+    "configure_shared_runtime",
     "init_logging",
+    "py_to_identity_to_py",
     "py_to_rust_to_py",
+    "set_signal_check_interval",
     "ConvexInt64",
+    "ConvexConversionError",
+    "ConvexTimeoutError",
 ]
 from ._convex import (
+    PyAuthState,
     PyConvexClient,
     PyQuerySetSubscription,
     PyQuerySubscription,
+    configure_shared_runtime,
     init_logging,
+    py_to_identity_to_py,
     py_to_rust_to_py,
+    set_signal_check_interval,
 )
+from .errors import ConvexConversionError, ConvexTimeoutError
 from .int64 import ConvexInt64
@@ -3,7 +3,8 @@
 # These types are defined in `crates/py_client/src/client/mod.rs` and `crates/py_client/src/client/subscription.rs`.
 # Types in this file will need to be manually updated when these pyo3-annotated structs change.
 
-from typing import Any, Awaitable, Dict, Literal, Optional, Union
+import os
+from typing import Any, Awaitable, Callable, Dict, Literal, Mapping, Optional, Union
 
 from typing_extensions import TypedDict
 
@@ -18,30 +19,109 @@ class ConvexErrorResult(TypedDict):
 
 Result = Union[ValueResult, ConvexErrorResult]
 
+IntPolicy = Literal["strict", "float", "int64"]
+
 class PyQuerySubscription:
     def exists(self) -> bool: ...
     @property
     def id(self) -> Any: ...
+    @property
+    def int64_as_int(self) -> bool: ...
     def unsubscribe(self) -> None: ...
     def next(self) -> Result: ...
     def anext(self) -> Awaitable[Result]: ...
 
 class PyQuerySetSubscription:
     def exists(self) -> bool: ...
+    @property
+    def int64_as_int(self) -> bool: ...
     def next(self) -> Dict[Any, Any]: ...
     def anext(self) -> Awaitable[Dict[Any, Any]]: ...
 
+class PyAuthState:
+    @property
+    def status(
+        self,
+    ) -> Literal["cleared", "pending", "accepted", "expired"]: ...
+
 class PyConvexClient:
-    def __new__(cls, deployment_url: str) -> "PyConvexClient": ...
+    def __new__(
+        cls,
+        deployment_url: str,
+        timeout: Optional[float] = None,
+        shared_runtime: bool = False,
+        offload_nested_calls: bool = False,
+        int_policy: IntPolicy = "strict",
+        int64_as_int: bool = False,
+    ) -> "PyConvexClient": ...
+    @staticmethod
+    def from_env(
+        env_file: Union[str, "os.PathLike[str]"] = ".env.local",
+        require_admin_key: bool = False,
+        **kwargs: Any,
+    ) -> "PyConvexClient": ...
     def subscribe(
-        self, name: str, args: Optional[Dict[str, Any]] = None
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+        int64_as_int: Optional[bool] = None,
     ) -> PyQuerySubscription: ...
-    def query(self, name: str, args: Optional[Dict[str, Any]] = None) -> Result: ...
-    def mutation(self, name: str, args: Optional[Dict[str, Any]] = None) -> Result: ...
-    def action(self, name: str, args: Optional[Dict[str, Any]] = None) -> Result: ...
-    def watch_all(self) -> PyQuerySetSubscription: ...
+    def query(
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+    ) -> Result: ...
+    def mutation(
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+    ) -> Result: ...
+    def action(
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+    ) -> Result: ...
+    def aquery(
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+    ) -> Awaitable[Result]: ...
+    def amutation(
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+    ) -> Awaitable[Result]: ...
+    def aaction(
+        self,
+        name: str,
+        args: Optional[Mapping[str, Any]] = None,
+        timeout: Optional[float] = None,
+    ) -> Awaitable[Result]: ...
+    def watch_all(
+        self, int64_as_int: Optional[bool] = None
+    ) -> PyQuerySetSubscription: ...
     def set_auth(self, token: Optional[str]) -> None: ...
-    def set_admin_auth(self, token: str) -> None: ...
+    def set_auth_callback(self, fetch_token: Callable[..., Optional[str]]) -> None: ...
+    def set_admin_auth(
+        self, token: str, acting_as: Optional[Dict[str, Any]] = None
+    ) -> None: ...
+    @property
+    def auth_state(self) -> PyAuthState: ...
+    def on_auth_state_change(self, callback: Callable[[PyAuthState], None]) -> None: ...
+    def close(self, timeout: Optional[float] = None) -> None: ...
+    def aclose(self, timeout: Optional[float] = None) -> Awaitable[None]: ...
+    def __enter__(self) -> "PyConvexClient": ...
+    def __exit__(self, exc_type: Any, exc_value: Any, traceback: Any) -> None: ...
+    def __aenter__(self) -> Awaitable["PyConvexClient"]: ...
+    def __aexit__(
+        self, exc_type: Any, exc_value: Any, traceback: Any
+    ) -> Awaitable[None]: ...
 
 def init_logging() -> None:
     """
@@ -49,5 +129,25 @@ def init_logging() -> None:
     stdout.
     """
 
-def py_to_rust_to_py(value: Any) -> Any:
+def configure_shared_runtime(worker_threads: int) -> None:
+    """
+    Configure the runtime shared by clients created with `shared_runtime=True`
+    and by awaitables to run `worker_threads` worker threads.
+
+    Raises a `RuntimeError` once a client or an awaitable has started it.
+    """
+
+def set_signal_check_interval(seconds: float) -> None:
+    """
+    Set how often blocking calls made from the main thread check whether a
+    signal handler raised an exception, such as `KeyboardInterrupt`. Defaults
+    to 1 second. On Unix, signals also wake blocking calls up right away.
+    """
+
+def py_to_rust_to_py(
+    value: Any, int_policy: IntPolicy = "strict", int64_as_int: bool = False
+) -> Any:
     """Convert a Python value to Rust and bring it back to test conversions."""
+
+def py_to_identity_to_py(acting_as: Dict[str, Any]) -> Dict[str, Any]:
+    """Convert `acting_as` to an identity and bring it back to test conversions."""
@@ -0,0 +1,20 @@
+# pyo3 can't define new custom exceptions when using the common abi
+# `features = ["abi3"]` so exceptions raised from Rust are defined here.
+
+
+class ConvexTimeoutError(TimeoutError):
+    """Raised when a Convex call does not complete within its timeout."""
+
+
+class ConvexConversionError(TypeError, ValueError):
+    """Raised when a value can't be converted between Python and Convex.
+
+    It is a `TypeError` or a `ValueError`, like the errors raised by
+    `coerce_to_convex()` for unsupported types or values out of range.
+    `path` locates the value which can't be converted, like
+    `args.items[3].price` for a function argument.
+    """
+
+    def __init__(self, message: str, path: str):
+        super().__init__(message)
+        self.path = path
@@ -2,14 +2,19 @@
 
 from __future__ import annotations
 
-import logging
-from typing import Any, Dict, Optional
+import os
+from typing import Any, Callable, Dict, Literal, Mapping, Optional, Union
 
 from _convex import (
+    ConvexConversionError,
+    ConvexTimeoutError,
+    PyAuthState,
     PyConvexClient,
     PyQuerySetSubscription,
     PyQuerySubscription,
+    configure_shared_runtime,
     init_logging,
+    set_signal_check_interval,
 )
 
 from .values import (
@@ -17,7 +22,6 @@ from .values import (
     ConvexInt64,
     ConvexValue,
     JsonValue,
-    coerce_args_to_convex,
     convex_to_json,
     json_to_convex,
 )
@@ -32,8 +36,13 @@ __all__ = [
     "convex_to_json",
     "json_to_convex",
     "ConvexError",
+    "ConvexConversionError",
+    "ConvexTimeoutError",
     "ConvexClient",
+    "AuthState",
     "ConvexInt64",
+    "configure_shared_runtime",
+    "set_signal_check_interval",
 ]
 
 __version__ = "0.6.0"  # Also update in pyproject.toml
@@ -60,8 +69,11 @@ class ConvexExecutionError(Exception):
     """Convex execution error on server."""
 
 
-FunctionArgs = Optional[Dict[str, CoercibleToConvexValue]]
+FunctionArgs = Optional[Mapping[str, CoercibleToConvexValue]]
 SubscriberId = Any
+AuthState = PyAuthState
+FetchToken = Callable[..., Optional[str]]
+IntPolicy = Literal["strict", "float", "int64"]
 
 
 class QuerySubscription:
@@ -86,20 +98,14 @@ class QuerySubscription:
         self.args: FunctionArgs = args
         self.invalidated: bool = False
 
-    # This addresses the "hitting ctrl-c while subscribed" issue
-    # described in https://github.com/get-convex/convex/pull/18559.
-    # If our program is interrupted/crashes while we're awaiting on a function
-    # result from our backend, we might end up with a lost subscription.
-    # This safeguard checks to make sure the inner subscription on the
-    # Rust side exists. If not, we first re-subscribe.
+    # The inner subscription is kept when hitting ctrl-c while waiting for a
+    # result (see https://github.com/get-convex/convex/pull/18559), so it only
+    # stops existing when unsubscribed or when the client is closed. Iterating
+    # then stops rather than subscribing again.
     def safe_inner_sub(self) -> PyQuerySubscription:
         # Check if the subscription was unsubscribed.
         if self.invalidated:
             raise Exception("This subscription has been dropped")
-        # In what situation would this occur? Why resubscribe?
-        if not self.inner.exists():
-            logging.warn("Retarting subscription dropped during possible ctrl-c")
-            self.inner = self.client.subscribe(self.name, self.args)
         return self.inner
 
     @property
@@ -151,13 +157,15 @@ class QuerySetSubscription:
     or an error message/`ConvexError` if execution did not succeed.
     """
 
-    def __init__(self, inner: PyQuerySetSubscription, client: PyConvexClient):
+    def __init__(
+        self,
+        inner: PyQuerySetSubscription,
+        client: PyConvexClient,
+    ):
         self.inner: PyQuerySetSubscription = inner
         self.client: PyConvexClient = client
 
     def safe_inner_sub(self) -> PyQuerySetSubscription:
-        if not self.inner.exists():
-            self.inner = self.client.watch_all()
         return self.inner
 
     def __iter__(self) -> QuerySetSubscription:
@@ -188,63 +196,263 @@ class ConvexClient:
 
     A client to interact with a Convex deployment to perform
     queries/mutations/actions and manage query subscriptions.
+
+    A client can be shared by several threads, whose calls run concurrently
+    over the same WebSocket connection.
+
+    A client doesn't survive `os.fork()`: using it in the child process raises
+    a `RuntimeError`, as do awaitables and clients with `shared_runtime=True`
+    once the shared runtime started before the fork. Pre-fork servers and
+    `multiprocessing` workers should create their clients after forking.
     """
 
     # This client wraps PyConvexClient by
-    # - implementing additional type convertions (e.g. tuples to arrays)
+    # - raising ConvexError for function results of type "convexerror"
+    # - wrapping subscriptions in iterable QuerySubscription objects
     # - making arguments dicts optional
 
-    def __init__(self, deployment_url: str):
-        """Construct a WebSocket-based client given the URL of a Convex deployment."""
-        self.client: PyConvexClient = PyConvexClient(deployment_url)
+    def __init__(
+        self,
+        deployment_url: str,
+        timeout: Optional[float] = None,
+        shared_runtime: bool = False,
+        offload_nested_calls: bool = False,
+        int_policy: IntPolicy = "strict",
+        int64_as_int: bool = False,
+    ):
+        """Construct a WebSocket-based client given the URL of a Convex deployment.
+
+        Raises a `ValueError` describing the problem if `deployment_url` isn't
+        the URL of a deployment, like `https://happy-animal-123.convex.cloud`.
+
+        `timeout` is the default number of seconds to wait for queries,
+        mutations, actions and subscriptions, blocking or awaited, before
+        raising a `ConvexTimeoutError`. By default calls wait indefinitely.
+
+        With `shared_runtime`, the client runs on a runtime shared by every such
+        client and by `aquery()` and other awaitables, instead of starting its
+        own thread. Call `configure_shared_runtime()` before creating such a
+        client or any awaitable to choose how many threads it runs.
+
+        Blocking calls can't be made from async code run by the client, such as
+        state change callbacks, and raise a `RuntimeError` there. Use the async
+        methods instead, or pass `offload_nested_calls=True` to make such calls
+        from a separate thread.
+
+        Ints in function arguments are sent as floats, which can't represent
+        ints beyond 2^53 exactly. `int_policy` chooses what happens to those:
+        `"strict"` raises a `ConvexConversionError`, `"float"` rounds them, and
+        `"int64"` sends them as Int64s, which functions receive as `BigInt`s.
+
+        Int64 values in results are returned as `ConvexInt64`s, which are sent
+        back as Int64s. With `int64_as_int=True` they are returned as plain
+        ints instead, which subscriptions can override.
+        """
+        self.client: PyConvexClient = PyConvexClient(
+            deployment_url,
+            timeout,
+            shared_runtime,
+            offload_nested_calls,
+            int_policy,
+            int64_as_int,
+        )
+
+    @classmethod
+    def from_env(
+        cls,
+        env_file: Union[str, "os.PathLike[str]"] = ".env.local",
+        require_admin_key: bool = False,
+        timeout: Optional[float] = None,
+        shared_runtime: bool = False,
+        offload_nested_calls: bool = False,
+        int_policy: IntPolicy = "strict",
+        int64_as_int: bool = False,
+    ) -> ConvexClient:
+        """Construct a client for the deployment configured in the environment.
+
+        Variables are read from the environment, then from `env_file`, which
+        defaults to the `.env.local` file the Convex CLI writes.
+
+        The deployment URL is `CONVEX_URL` if set, otherwise the deployment
+        named by `CONVEX_DEPLOY_KEY` or `CONVEX_DEPLOYMENT`, like
+        `dev:happy-animal-123`. `CONVEX_DEPLOY_KEY` is set as admin auth if
+        present, and must be with `require_admin_key=True`. A `ValueError`
+        naming the variables which were looked for is raised if they're missing.
+
+        Other arguments are the same as for the constructor.
+        """
+        client = cls.__new__(cls)
+        client.client = PyConvexClient.from_env(
+            env_file,
+            require_admin_key,
+            timeout=timeout,
+            shared_runtime=shared_runtime,
+            offload_nested_calls=offload_nested_calls,
+            int_policy=int_policy,
+            int64_as_int=int64_as_int,
+        )
+        return client
+
+    def subscribe(
+        self,
+        name: str,
+        args: FunctionArgs = None,
+        timeout: Optional[float] = None,
+        int64_as_int: Optional[bool] = None,
+    ) -> QuerySubscription:
+        """Return a to subscription to the query `name` with optional `args`.
 
-    def subscribe(self, name: str, args: FunctionArgs = None) -> QuerySubscription:
-        """Return a to subscription to the query `name` with optional `args`."""
-        subscription = self.client.subscribe(name, args if args else {})
+        `int64_as_int` overrides whether Int64 values in results are returned
+        as plain ints for this subscription.
+        """
+        subscription = self.client.subscribe(
+            name, args if args else {}, timeout, int64_as_int
+        )
         return QuerySubscription(subscription, self.client, name, args if args else {})
 
     # Return Any because its more useful than the big union type ConvexValue.
-    def query(self, name: str, args: FunctionArgs = None) -> Any:
+    def query(
+        self, name: str, args: FunctionArgs = None, timeout: Optional[float] = None
+    ) -> Any:
         """Perform the query `name` with `args` returning the result."""
-        result = self.client.query(name, coerce_args_to_convex(args))
+        result = self.client.query(name, args, timeout)
         if result["type"] == "convexerror":
             raise ConvexError(result["message"], result["data"])
         return result["value"]
 
-    def mutation(self, name: str, args: FunctionArgs = None) -> Any:
+    def mutation(
+        self, name: str, args: FunctionArgs = None, timeout: Optional[float] = None
+    ) -> Any:
         """Perform the mutation `name` with `args` returning the result."""
-        result = self.client.mutation(name, coerce_args_to_convex(args))
+        result = self.client.mutation(name, args, timeout)
         if result["type"] == "convexerror":
             raise ConvexError(result["message"], result["data"])
         return result["value"]
 
-    def action(self, name: str, args: FunctionArgs = None) -> Any:
+    def action(
+        self, name: str, args: FunctionArgs = None, timeout: Optional[float] = None
+    ) -> Any:
         """Perform the action `name` with `args` returning the result."""
-        result = self.client.action(name, coerce_args_to_convex(args))
+        result = self.client.action(name, args, timeout)
+        if result["type"] == "convexerror":
+            raise ConvexError(result["message"], result["data"])
+        return result["value"]
+
+    async def aquery(
+        self, name: str, args: FunctionArgs = None, timeout: Optional[float] = None
+    ) -> Any:
+        """Perform the query `name` with `args` without blocking the event loop."""
+        result = await self.client.aquery(name, args, timeout)
+        if result["type"] == "convexerror":
+            raise ConvexError(result["message"], result["data"])
+        return result["value"]
+
+    async def amutation(
+        self, name: str, args: FunctionArgs = None, timeout: Optional[float] = None
+    ) -> Any:
+        """Perform the mutation `name` with `args` without blocking the event loop."""
+        result = await self.client.amutation(name, args, timeout)
         if result["type"] == "convexerror":
             raise ConvexError(result["message"], result["data"])
         return result["value"]
 
-    def watch_all(self) -> QuerySetSubscription:
+    async def aaction(
+        self, name: str, args: FunctionArgs = None, timeout: Optional[float] = None
+    ) -> Any:
+        """Perform the action `name` with `args` without blocking the event loop."""
+        result = await self.client.aaction(name, args, timeout)
+        if result["type"] == "convexerror":
+            raise ConvexError(result["message"], result["data"])
+        return result["value"]
+
+    def watch_all(self, int64_as_int: Optional[bool] = None) -> QuerySetSubscription:
         """Return a QuerySetSubscription of all currently subscribed queries.
 
         This set changes over time as subscriptions are added and dropped.
+        `int64_as_int` overrides whether Int64 values in results are returned
+        as plain ints.
         """
-        set_subscription: PyQuerySetSubscription = self.client.watch_all()
+        set_subscription: PyQuerySetSubscription = self.client.watch_all(int64_as_int)
         return QuerySetSubscription(set_subscription, self.client)
 
-    def set_auth(self, token: str) -> None:
+    def set_auth(self, token: Union[str, FetchToken]) -> None:
         """Set auth for use when calling Convex functions.
 
         Set it with a token that you get from your auth provider via their login
         flow. If `None` is passed as the token, then auth is unset (logging out).
+
+        To keep auth fresh, pass a function which fetches a token from your auth
+        provider instead. It's called with a `force_refresh_token` keyword
+        argument right away and shortly before each token expires.
+
+        The function isn't called when the WebSocket reconnects or when the
+        deployment rejects a token: the underlying client reports neither. A
+        token which is rejected although it hasn't expired is only replaced
+        shortly before it expires.
         """
-        self.client.set_auth(token)
+        if callable(token):
+            self.client.set_auth_callback(token)
+        else:
+            self.client.set_auth(token)
 
     def clear_auth(self) -> None:
         """Clear any auth previously set."""
         self.client.set_auth(None)
 
-    def set_admin_auth(self, admin_key: str) -> None:
-        """Set admin auth for the deployment. Not typically required."""
-        self.client.set_admin_auth(admin_key)
+    def set_admin_auth(
+        self, admin_key: str, acting_as: Optional[Dict[str, Any]] = None
+    ) -> None:
+        """Set admin auth for the deployment. Not typically required.
+
+        To call functions as a user would, pass the user's identity as
+        `acting_as`, keyed like the `UserIdentity` returned by
+        `ctx.auth.getUserIdentity()`, e.g.
+        `{"subject": "user123", "issuer": "https://auth.example.com"}`.
+        Other keys are passed as custom claims.
+        """
+        self.client.set_admin_auth(admin_key, acting_as)
+
+    @property
+    def auth_state(self) -> AuthState:
+        """Return the state of the auth set on this client.
+
+        The state's `status` is "cleared", "pending", "accepted" or "expired".
+        Auth is accepted once a query, mutation or action completes with it.
+        The underlying client doesn't report auth the deployment rejects, which
+        stays pending. Malformed auth is never set, leaving the state as is.
+        """
+        return self.client.auth_state
+
+    def on_auth_state_change(self, callback: Callable[[AuthState], None]) -> None:
+        """Call `callback` with the new state whenever the auth state changes.
+
+        The callback runs on a background thread.
+        """
+        self.client.on_auth_state_change(callback)
+
+    def close(self, timeout: Optional[float] = None) -> None:
+        """Close the client and its WebSocket connection.
+
+        Unsubscribes all subscriptions made by this client, which stop
+        iterating, and waits up to `timeout` seconds (5 by default) for
+        in-flight mutations to complete. Other calls still waiting, like those
+        made from other threads, raise a `RuntimeError`. The client can't be
+        used after it has been closed.
+        """
+        self.client.close(timeout)
+
+    async def aclose(self, timeout: Optional[float] = None) -> None:
+        """Close the client without blocking the event loop. See `close()`."""
+        await self.client.aclose(timeout)
+
+    def __enter__(self) -> ConvexClient:
+        return self
+
+    def __exit__(self, *exc_info: Any) -> None:
+        self.close()
+
+    async def __aenter__(self) -> ConvexClient:
+        return self
+
+    async def __aexit__(self, *exc_info: Any) -> None:
+        await self.aclose()
@@ -57,11 +57,10 @@ ConvexValue = Union[
     "ConvexInt64",
 ]
 
-# Currently in the normal WebSocket client:
-# Many Python values --coerce_to_convex()--> Specific Python values, e.g. converting tuples to lists
-# Specific Python values --py_to_value()--> Rust values, converting ConvxInt64 -> i64 and int -> f64
-
-# Better future state for the WebSocket client: no coersion takes place in Python, it's all in Rust.
+# The WebSocket client coerces values in Rust: py_to_value() converts Python
+# values to Rust values with the same rules as coerce_to_convex(), e.g.
+# converting tuples to lists, ConvexInt64 -> i64 and int -> f64. Changes to
+# these rules need to be made in both places.
 
 # This should be a wider type: it also includes objects that implement the buffer protocol.
 CoercibleToConvexValue = Union[
@@ -222,7 +221,7 @@ def coerce_to_convex(v: CoercibleToConvexValue) -> ConvexValue:
     return _to_convex(v, True)
 
 
-# This coercion logic would be better to have in Rust
+# The WebSocket client implements this coercion logic in Rust, in py_to_value().
 def _to_convex(v: CoercibleToConvexValue, coerce: bool) -> ConvexValue:
     """
     Convert to the types expected by the wrapped Rust client.
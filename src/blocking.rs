@@ -0,0 +1,280 @@
+//! Blocking Python threads on async code.
+//!
+//! Blocking on a runtime from a thread which is already running async code,
+//! like the worker threads of a runtime calling back into Python, panics.
+//!
+//! While blocked, the main thread checks for signals so that Ctrl-C
+//! interrupts a blocking call. Signal handlers only run on the main thread, so
+//! other threads don't check. On Unix, the call borrows Python's signal wakeup
+//! fd to be woken up as soon as a signal arrives, and otherwise polls.
+
+use std::{
+    future::Future,
+    panic,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    thread::{
+        self,
+        ThreadId,
+    },
+};
+#[cfg(unix)]
+use std::{
+    io::Read,
+    mem,
+    os::unix::{
+        io::AsRawFd,
+        net::UnixStream,
+    },
+};
+
+use futures::future;
+use parking_lot::Mutex;
+#[cfg(unix)]
+use pyo3::types::PyBytes;
+use pyo3::{
+    exceptions::{
+        PyRuntimeError,
+        PyValueError,
+    },
+    prelude::*,
+};
+#[cfg(unix)]
+use tokio::io::{
+    unix::AsyncFd,
+    Interest,
+};
+use tokio::{
+    runtime::{
+        Handle,
+        RuntimeFlavor,
+    },
+    task,
+    time::{
+        sleep,
+        Duration,
+    },
+};
+
+use crate::fork::OwningProcess;
+
+/// How often blocked calls poll for signals, in microseconds.
+static SIGNAL_CHECK_INTERVAL_US: AtomicU64 = AtomicU64::new(1_000_000);
+
+/// The main thread, once a blocking call has been made from it. A child
+/// process forked from another thread has a different main thread.
+static MAIN_THREAD: Mutex<Option<(OwningProcess, ThreadId)>> = Mutex::new(None);
+
+/// Set how often, in seconds, blocking calls made from the main thread check
+/// whether a signal handler raised an exception, such as `KeyboardInterrupt`
+/// on Ctrl-C. Defaults to 1 second.
+///
+/// On Unix, signals wake blocking calls up right away, so this only matters
+/// if Python's signal wakeup fd can't be set.
+#[pyfunction]
+pub fn set_signal_check_interval(seconds: f64) -> PyResult<()> {
+    let interval = Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|interval| !interval.is_zero())
+        .ok_or_else(|| PyValueError::new_err(format!("Invalid interval: {seconds}")))?;
+    let micros = u64::try_from(interval.as_micros())
+        .unwrap_or(u64::MAX)
+        .max(1);
+    SIGNAL_CHECK_INTERVAL_US.store(micros, Ordering::Relaxed);
+    Ok(())
+}
+
+fn is_main_thread(py: Python<'_>) -> PyResult<bool> {
+    let current = thread::current().id();
+    if let Some((process, main)) = *MAIN_THREAD.lock() {
+        if process.is_current() {
+            return Ok(main == current);
+        }
+    }
+    let threading = py.import("threading")?;
+    let main_thread = threading.call_method0("main_thread")?;
+    let is_main = threading.call_method0("current_thread")?.is(main_thread);
+    if is_main {
+        *MAIN_THREAD.lock() = Some((OwningProcess::current(), current));
+    }
+    Ok(is_main)
+}
+
+/// Python's signal wakeup fd, borrowed by a blocking call on the main thread.
+///
+/// The C signal handler of Python writes the number of every signal it receives
+/// to the wakeup fd. The previous wakeup fd, like the one of an asyncio event
+/// loop, is restored when dropped and sent the numbers of the signals received
+/// meanwhile, which asyncio relies on to run its signal handlers.
+#[cfg(unix)]
+struct SignalWakeup {
+    /// The end Python writes to.
+    _sender: UnixStream,
+    receiver: AsyncFd<UnixStream>,
+    /// The wakeup fd to restore, or -1 if there was none.
+    previous: i32,
+    received: Mutex<Vec<u8>>,
+}
+
+#[cfg(unix)]
+impl SignalWakeup {
+    /// Sets the wakeup fd, registering it with `rt`. Returns `None` if that
+    /// fails, in which case signals are only polled for.
+    fn install(py: Python<'_>, rt: &Handle) -> Option<Self> {
+        let (sender, receiver) = UnixStream::pair().ok()?;
+        sender.set_nonblocking(true).ok()?;
+        receiver.set_nonblocking(true).ok()?;
+        let receiver = {
+            let _guard = rt.enter();
+            AsyncFd::with_interest(receiver, Interest::READABLE).ok()?
+        };
+        let previous = py
+            .import("signal")
+            .and_then(|signal| signal.call_method1("set_wakeup_fd", (sender.as_raw_fd(),)))
+            .and_then(|previous| previous.extract())
+            .ok()?;
+        Some(SignalWakeup {
+            _sender: sender,
+            receiver,
+            previous,
+            received: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Resolves once a signal is received.
+    async fn woken(&self) {
+        let mut buf = [0; 64];
+        loop {
+            let Ok(mut guard) = self.receiver.readable().await else {
+                return future::pending().await;
+            };
+            match guard.try_io(|receiver| Read::read(&mut receiver.get_ref(), &mut buf)) {
+                Ok(Ok(n)) if n > 0 => {
+                    self.received.lock().extend_from_slice(&buf[..n]);
+                    return;
+                },
+                Ok(_) => return future::pending().await,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SignalWakeup {
+    fn drop(&mut self) {
+        let mut received = mem::take(self.received.get_mut());
+        let mut buf = [0; 64];
+        while let Ok(n) = Read::read(&mut self.receiver.get_ref(), &mut buf) {
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        Python::with_gil(|py| {
+            let restored = py
+                .import("signal")
+                .and_then(|signal| signal.call_method1("set_wakeup_fd", (self.previous,)));
+            if let Err(e) = restored {
+                e.print(py);
+                return;
+            }
+            if self.previous >= 0 && !received.is_empty() {
+                // The previous wakeup fd is non-blocking, so this can only fail
+                // when its buffer is full, when no wakeup is missing anyway.
+                let _ = py.import("os").and_then(|os| {
+                    os.call_method1("write", (self.previous, PyBytes::new(py, &received)))
+                });
+            }
+        });
+    }
+}
+
+/// Signals are only polled for on platforms without a wakeup fd which can be
+/// waited on.
+#[cfg(not(unix))]
+struct SignalWakeup;
+
+#[cfg(not(unix))]
+impl SignalWakeup {
+    fn install(_py: Python<'_>, _rt: &Handle) -> Option<Self> {
+        None
+    }
+
+    async fn woken(&self) {
+        future::pending().await
+    }
+}
+
+/// Resolves to the exception raised by a signal handler, or never resolves if
+/// signals aren't checked.
+async fn interrupted(check_signals: bool, wakeup: Option<&SignalWakeup>) -> PyErr {
+    if !check_signals {
+        return future::pending().await;
+    }
+    loop {
+        let interval = SIGNAL_CHECK_INTERVAL_US.load(Ordering::Relaxed);
+        let woken = async {
+            match wakeup {
+                Some(wakeup) => wakeup.woken().await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select!(
+            _ = sleep(Duration::from_micros(interval)) => {},
+            _ = woken => {},
+        );
+        if let Err(e) = Python::with_gil(|py| py.check_signals()) {
+            return e;
+        }
+    }
+}
+
+/// Block the current thread on `future`, which runs on `rt`.
+///
+/// When a signal handler raises, the future is dropped and the exception is
+/// returned. Dropping a query unsubscribes from it. Mutations and actions run
+/// in tasks of their own, which complete in the background instead.
+///
+/// When called from async code, this raises an error unless `offload` is set,
+/// in which case the future is blocked on from a separate thread.
+pub fn block_on<F, T>(rt: &Handle, offload: bool, future: F) -> PyResult<T>
+where
+    F: Future<Output = PyResult<T>> + Send,
+    T: Send,
+{
+    let current = Handle::try_current().ok();
+    if current.is_some() && !offload {
+        return Err(PyRuntimeError::new_err(
+            "Blocking calls can't be made from async code, such as callbacks run by the \
+             client. Use the async methods instead, or create the client with \
+             offload_nested_calls=True to make blocking calls from a separate thread.",
+        ));
+    }
+    let (check_signals, wakeup) = Python::with_gil(|py| -> PyResult<_> {
+        let check_signals = is_main_thread(py)?;
+        let wakeup = check_signals
+            .then(|| SignalWakeup::install(py, rt))
+            .flatten();
+        Ok((check_signals, wakeup))
+    })?;
+    let future = async {
+        tokio::select!(
+            res = future => res,
+            err = interrupted(check_signals, wakeup.as_ref()) => Err(err),
+        )
+    };
+    let Some(current) = current else {
+        return rt.block_on(future);
+    };
+    let offloaded = || thread::scope(|s| s.spawn(|| rt.block_on(future)).join());
+    let res = match current.runtime_flavor() {
+        // Let the runtime move its other tasks to another worker while this one
+        // is blocked, as the future may be waiting for them.
+        RuntimeFlavor::MultiThread => task::block_in_place(offloaded),
+        _ => offloaded(),
+    };
+    res.unwrap_or_else(|e| panic::resume_unwind(e))
+}
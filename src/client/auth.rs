@@ -0,0 +1,144 @@
+use std::{
+    cmp,
+    sync::Arc,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use convex::ConvexClient;
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::PyDict,
+};
+use tokio::{
+    runtime::Handle,
+    task::{
+        self,
+        JoinHandle,
+    },
+    time::{
+        sleep,
+        Duration,
+    },
+};
+
+use super::auth_state::AuthStateTracker;
+
+/// How long before a token expires to fetch a new one.
+const REFRESH_LEEWAY: Duration = Duration::from_secs(10);
+/// Minimum time between two refreshes, so that a token provider returning
+/// tokens which are already (nearly) expired isn't called in a tight loop.
+const MIN_REFRESH_DELAY: Duration = Duration::from_secs(5);
+
+/// Fetch a token by calling `fetch_token(force_refresh_token=...)`.
+pub fn fetch_token(
+    py: Python<'_>,
+    fetch_token: &PyObject,
+    force_refresh_token: bool,
+) -> PyResult<Option<String>> {
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("force_refresh_token", force_refresh_token)?;
+    fetch_token.call(py, (), Some(kwargs))?.extract(py)
+}
+
+/// Decodes a base64url-encoded JSON segment of a JWT.
+fn decode_segment(segment: &str) -> Option<serde_json::Value> {
+    let segment = base64::decode_config(segment, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&segment).ok()
+}
+
+/// Checks that `token` is a well-formed JWT, which is what Convex expects.
+///
+/// The signature isn't checked: whether the token is accepted is up to the
+/// deployment.
+pub fn validate_token(token: &str) -> PyResult<()> {
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 3 {
+        return Err(PyValueError::new_err(format!(
+            "Malformed auth token: expected a JWT with 3 dot-separated segments, found {}",
+            segments.len()
+        )));
+    }
+    if decode_segment(segments[0]).is_none() || decode_segment(segments[1]).is_none() {
+        return Err(PyValueError::new_err(
+            "Malformed auth token: the JWT header and payload must be base64url-encoded JSON",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `key` looks like a deploy key.
+pub fn validate_admin_key(key: &str) -> PyResult<()> {
+    if key.is_empty() {
+        return Err(PyValueError::new_err(
+            "Malformed admin key: the key is empty",
+        ));
+    }
+    if key.chars().any(char::is_whitespace) {
+        return Err(PyValueError::new_err(
+            "Malformed admin key: the key contains whitespace",
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the `exp` claim of a JWT, without validating the token.
+pub fn token_expiry(token: &str) -> Option<SystemTime> {
+    let claims = decode_segment(token.split('.').nth(1)?)?;
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+/// Resolves shortly before `expiry`, or never if the token doesn't expire.
+async fn refresh_before(expiry: Option<SystemTime>) {
+    let Some(expiry) = expiry else {
+        return futures::future::pending().await;
+    };
+    let until_refresh = expiry
+        .checked_sub(REFRESH_LEEWAY)
+        .and_then(|refresh_at| refresh_at.duration_since(SystemTime::now()).ok())
+        .unwrap_or_default();
+    sleep(cmp::max(until_refresh, MIN_REFRESH_DELAY)).await;
+}
+
+/// Keeps the auth of `client` up to date by calling `fetch` for a new token
+/// shortly before the current one expires.
+///
+/// The underlying client doesn't report reconnects or auth errors, so a token
+/// rejected by the server is only replaced shortly before it expires.
+pub fn spawn_refresher(
+    rt: &Handle,
+    mut client: ConvexClient,
+    fetch: PyObject,
+    mut token: Option<String>,
+    auth_state: AuthStateTracker,
+) -> JoinHandle<()> {
+    let fetch = Arc::new(fetch);
+    rt.spawn(async move {
+        loop {
+            refresh_before(token.as_deref().and_then(token_expiry)).await;
+            let fetch = fetch.clone();
+            // Token providers may be slow, so they run on a blocking thread
+            // rather than the worker driving the client.
+            let fetched = task::spawn_blocking(move || {
+                Python::with_gil(|py| fetch_token(py, &fetch, true).map_err(|e| e.print(py)))
+            })
+            .await;
+            // Keep the current token if fetching a new one failed, or if the
+            // new one is malformed.
+            let Ok(Ok(fetched)) = fetched else {
+                continue;
+            };
+            if let Some(Err(e)) = fetched.as_deref().map(validate_token) {
+                Python::with_gil(|py| e.print(py));
+                continue;
+            }
+            token = fetched;
+            auth_state.set_token(&Handle::current(), token.as_deref());
+            client.set_auth(token.clone()).await;
+        }
+    })
+}
@@ -0,0 +1,150 @@
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use pyo3::prelude::*;
+use tokio::{
+    runtime::Handle,
+    sync::watch,
+    task,
+    time::sleep,
+};
+
+use super::auth::token_expiry;
+
+/// The state of the auth set on a client.
+///
+/// `status` is one of:
+/// - "cleared": no auth is set.
+/// - "pending": auth is set but no function has run with it yet. The
+///   underlying client doesn't report rejected auth, so auth the deployment
+///   rejects stays pending.
+/// - "accepted": a function ran with the auth, so the deployment accepted it.
+/// - "expired": the token expired without being replaced.
+///
+/// Malformed auth is never set, so it leaves the state unchanged.
+#[pyclass(frozen)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PyAuthState {
+    #[pyo3(get)]
+    status: &'static str,
+}
+
+#[pymethods]
+impl PyAuthState {
+    fn __repr__(&self) -> String {
+        format!("AuthState(status={:?})", self.status)
+    }
+}
+
+impl PyAuthState {
+    fn new(status: &'static str) -> Self {
+        PyAuthState { status }
+    }
+}
+
+/// Calls `callback` with every new value sent on `rx`, until the sender is
+/// dropped or the runtime is shut down.
+fn spawn_on_change<T>(rt: &Handle, mut rx: watch::Receiver<T>, callback: PyObject)
+where
+    T: Clone + IntoPy<PyObject> + Send + Sync + 'static,
+{
+    let callback = Arc::new(callback);
+    rt.spawn(async move {
+        while rx.changed().await.is_ok() {
+            let state = rx.borrow_and_update().clone();
+            let callback = callback.clone();
+            // The callback runs on a blocking thread rather than a worker of the
+            // runtime, which also drives the client while the GIL is held.
+            let _ = task::spawn_blocking(move || {
+                Python::with_gil(|py| {
+                    if let Err(e) = callback.call1(py, (state,)) {
+                        e.print(py);
+                    }
+                })
+            })
+            .await;
+        }
+    });
+}
+
+/// Tracks the auth state of a client, as far as it can be observed.
+///
+/// The underlying client doesn't report whether the deployment accepted a
+/// token, so this is inferred from function calls completing.
+#[derive(Clone)]
+pub struct AuthStateTracker {
+    state: Arc<watch::Sender<PyAuthState>>,
+    /// Incremented whenever auth changes, so that expiry of a replaced token
+    /// is ignored.
+    generation: Arc<AtomicU64>,
+}
+
+impl AuthStateTracker {
+    pub fn new() -> Self {
+        AuthStateTracker {
+            state: Arc::new(watch::channel(PyAuthState::new("cleared")).0),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn state(&self) -> PyAuthState {
+        self.state.borrow().clone()
+    }
+
+    /// Calls `callback` with the new state on every state change.
+    pub fn on_change(&self, rt: &Handle, callback: PyObject) {
+        spawn_on_change(rt, self.state.subscribe(), callback);
+    }
+
+    /// Records that `token` was set, or auth was cleared if there is none.
+    pub fn set_token(&self, rt: &Handle, token: Option<&str>) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let Some(token) = token else {
+            self.state.send_replace(PyAuthState::new("cleared"));
+            return;
+        };
+        self.state.send_replace(PyAuthState::new("pending"));
+        let Some(expiry) = token_expiry(token) else {
+            return;
+        };
+        let until_expiry = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+        let tracker = self.clone();
+        rt.spawn(async move {
+            sleep(until_expiry).await;
+            if tracker.generation.load(Ordering::SeqCst) == generation {
+                tracker.state.send_replace(PyAuthState::new("expired"));
+            }
+        });
+    }
+
+    /// Records that admin auth was set. Admin keys don't expire.
+    pub fn set_admin_key(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.state.send_replace(PyAuthState::new("pending"));
+    }
+
+    /// Records the outcome of a function call made with the current auth.
+    ///
+    /// A call completing shows the auth was accepted. A failed call doesn't
+    /// show it was rejected, as it may have failed for any other reason.
+    pub fn observe<T, E>(&self, result: &Result<T, E>) {
+        if result.is_err() {
+            return;
+        }
+        self.state.send_if_modified(|state| {
+            if state.status != "pending" {
+                return false;
+            }
+            *state = PyAuthState::new("accepted");
+            true
+        });
+    }
+}
@@ -0,0 +1,100 @@
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+};
+use url::{
+    ParseError,
+    Url,
+};
+
+/// The domain deployments are served from on Convex cloud.
+const CLOUD_DOMAIN: &str = ".convex.cloud";
+/// The domain HTTP actions are served from on Convex cloud.
+const SITE_DOMAIN: &str = ".convex.site";
+
+fn invalid(url: &str, problem: &str, suggestion: Option<&str>) -> PyErr {
+    let mut message = format!("Invalid deployment URL {url:?}: {problem}.");
+    if let Some(suggestion) = suggestion {
+        message.push_str(&format!(" Did you mean {suggestion:?}?"));
+    }
+    PyValueError::new_err(message)
+}
+
+/// Checks that `url` is the URL of a deployment, like
+/// `https://happy-animal-123.convex.cloud`, so that a mistake raises a
+/// `ValueError` right away instead of failing to connect later.
+///
+/// The error describes the first problem found and, when possible, suggests a
+/// URL with every problem fixed.
+pub fn validate_deployment_url(url: &str) -> PyResult<()> {
+    let trimmed = url.trim();
+    if trimmed != url {
+        return Err(invalid(
+            url,
+            "it has leading or trailing whitespace",
+            Some(trimmed),
+        ));
+    }
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(ParseError::RelativeUrlWithoutBase) => {
+            let suggestion = format!("https://{}", url.trim_end_matches('/'));
+            return Err(invalid(url, "it has no scheme", Some(&suggestion)));
+        },
+        Err(e) => return Err(invalid(url, &e.to_string(), None)),
+    };
+    let Some(host) = parsed.host_str() else {
+        return Err(invalid(url, "it has no host", None));
+    };
+
+    let mut problems = Vec::new();
+    let mut secure = match parsed.scheme() {
+        "https" => true,
+        "http" => false,
+        scheme => {
+            problems.push(format!("the scheme must be https or http, not {scheme}"));
+            scheme != "ws"
+        },
+    };
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        problems.push("it contains credentials".to_string());
+    }
+    let host = match host.strip_suffix(SITE_DOMAIN) {
+        Some(name) => {
+            problems.push(format!(
+                "{SITE_DOMAIN} is the domain of HTTP actions, clients connect to {CLOUD_DOMAIN}"
+            ));
+            format!("{name}{CLOUD_DOMAIN}")
+        },
+        None => host.to_string(),
+    };
+    if host.ends_with(CLOUD_DOMAIN) && !secure {
+        problems.push(format!(
+            "deployments on {CLOUD_DOMAIN} are served over https"
+        ));
+        secure = true;
+    }
+    // A single trailing slash is harmless, anything more is a path.
+    if parsed.path() != "/" {
+        problems.push(format!(
+            "it has a path ({}), deployment URLs have none",
+            parsed.path()
+        ));
+    }
+    if parsed.query().is_some() {
+        problems.push("it has a query string".to_string());
+    }
+    if parsed.fragment().is_some() {
+        problems.push("it has a fragment".to_string());
+    }
+
+    let Some(problem) = problems.first() else {
+        return Ok(());
+    };
+    let scheme = if secure { "https" } else { "http" };
+    let port = parsed
+        .port()
+        .map_or(String::new(), |port| format!(":{port}"));
+    let suggestion = format!("{scheme}://{host}{port}");
+    Err(invalid(url, problem, Some(&suggestion)))
+}
@@ -0,0 +1,218 @@
+//! Resolving the deployment to connect to from environment variables and the
+//! `.env.local` file written by the Convex CLI.
+
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io,
+    path::Path,
+};
+
+use pyo3::{
+    exceptions::{
+        PyOSError,
+        PyValueError,
+    },
+    prelude::*,
+};
+
+const URL_VAR: &str = "CONVEX_URL";
+const DEPLOYMENT_VAR: &str = "CONVEX_DEPLOYMENT";
+const DEPLOY_KEY_VAR: &str = "CONVEX_DEPLOY_KEY";
+
+/// The deployment configured in the environment.
+pub struct EnvConfig {
+    pub deployment_url: String,
+    pub admin_key: Option<String>,
+}
+
+/// A variable and where it was set, for error messages. Values aren't included
+/// in messages since deploy keys are secret.
+struct Var {
+    name: &'static str,
+    value: String,
+    source: String,
+}
+
+impl Var {
+    fn invalid(&self, problem: &str) -> PyErr {
+        PyValueError::new_err(format!(
+            "Invalid {} set in {}: {problem}",
+            self.name, self.source
+        ))
+    }
+}
+
+/// Variables are looked up in the process environment first, then in the env
+/// file.
+struct Sources<'a> {
+    env_file: &'a Path,
+    /// `None` if the env file doesn't exist.
+    file_vars: Option<HashMap<String, String>>,
+}
+
+impl<'a> Sources<'a> {
+    fn load(env_file: &'a Path) -> PyResult<Self> {
+        let file_vars = match fs::read_to_string(env_file) {
+            Ok(contents) => Some(parse_env_file(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(PyOSError::new_err(format!(
+                    "Failed to read {}: {e}",
+                    env_file.display()
+                )))
+            },
+        };
+        Ok(Sources {
+            env_file,
+            file_vars,
+        })
+    }
+
+    /// Looks up `name`, treating empty values as unset.
+    fn get(&self, name: &'static str) -> Option<Var> {
+        if let Some(value) = env::var(name).ok().filter(|v| !v.is_empty()) {
+            return Some(Var {
+                name,
+                value,
+                source: "the environment".to_string(),
+            });
+        }
+        let value = self.file_vars.as_ref()?.get(name)?;
+        if value.is_empty() {
+            return None;
+        }
+        Some(Var {
+            name,
+            value: value.clone(),
+            source: self.env_file.display().to_string(),
+        })
+    }
+
+    /// Describes where `names` were looked for, when none of them was set.
+    fn not_found(&self, names: &[&str]) -> String {
+        let names = names.join(" or ");
+        match self.file_vars {
+            Some(_) => format!(
+                "{names} isn't set in the environment or in {}",
+                self.env_file.display()
+            ),
+            None => format!(
+                "{names} isn't set in the environment, and {} doesn't exist",
+                self.env_file.display()
+            ),
+        }
+    }
+}
+
+/// Parses the `KEY=value` lines of an env file. Values may be quoted, and
+/// unquoted values may be followed by a comment, like the one the Convex CLI
+/// writes after `CONVEX_DEPLOYMENT`.
+fn parse_env_file(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            Some((key.trim().to_string(), unquote(value.trim())))
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    for quote in ['"', '\''] {
+        if let Some((inner, _)) = value
+            .strip_prefix(quote)
+            .and_then(|rest| rest.split_once(quote))
+        {
+            return inner.to_string();
+        }
+    }
+    match value.split_once(" #") {
+        Some((value, _)) => value.trim_end().to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// The URL of the deployment named `name`, e.g. `happy-animal-123`.
+fn cloud_url(var: &Var, name: &str) -> PyResult<String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(var.invalid(&format!("{name:?} isn't a deployment name")));
+    }
+    Ok(format!("https://{name}.convex.cloud"))
+}
+
+/// The URL of the deployment a `CONVEX_DEPLOYMENT` like `dev:happy-animal-123`
+/// refers to.
+fn deployment_url(var: &Var) -> PyResult<String> {
+    let (kind, name) = var
+        .value
+        .split_once(':')
+        .unwrap_or(("", var.value.as_str()));
+    if kind == "local" || kind == "anonymous" {
+        return Err(var.invalid(&format!(
+            "the URL of {kind} deployments can't be resolved, set {URL_VAR} instead"
+        )));
+    }
+    cloud_url(var, name)
+}
+
+/// The URL of the deployment a deploy key like `prod:happy-animal-123|...`
+/// belongs to, if the key names one. Preview deploy keys name a project
+/// instead.
+fn deploy_key_url(var: &Var) -> PyResult<Option<String>> {
+    let Some((prefix, _)) = var.value.split_once('|') else {
+        return Ok(None);
+    };
+    match prefix.split(':').collect::<Vec<_>>()[..] {
+        ["prod" | "dev", name] => Ok(Some(cloud_url(var, name)?)),
+        _ => Ok(None),
+    }
+}
+
+impl EnvConfig {
+    /// Resolves the deployment URL from, in order of precedence, `CONVEX_URL`,
+    /// the deployment of `CONVEX_DEPLOY_KEY` and `CONVEX_DEPLOYMENT`, and the
+    /// admin key from `CONVEX_DEPLOY_KEY`.
+    pub fn resolve(env_file: &Path, require_admin_key: bool) -> PyResult<Self> {
+        let sources = Sources::load(env_file)?;
+        let deploy_key = sources.get(DEPLOY_KEY_VAR);
+        if require_admin_key && deploy_key.is_none() {
+            return Err(PyValueError::new_err(format!(
+                "No admin key found: {}",
+                sources.not_found(&[DEPLOY_KEY_VAR])
+            )));
+        }
+
+        let key_url = deploy_key
+            .as_ref()
+            .map(deploy_key_url)
+            .transpose()?
+            .flatten();
+        let deployment_url = if let Some(var) = sources.get(URL_VAR) {
+            var.value
+        } else if let Some(url) = key_url {
+            url
+        } else if let Some(var) = sources.get(DEPLOYMENT_VAR) {
+            deployment_url(&var)?
+        } else {
+            return Err(PyValueError::new_err(format!(
+                "No deployment URL found: {}",
+                sources.not_found(&[URL_VAR, DEPLOYMENT_VAR])
+            )));
+        };
+        Ok(EnvConfig {
+            deployment_url,
+            admin_key: deploy_key.map(|var| var.value),
+        })
+    }
+}
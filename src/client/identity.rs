@@ -0,0 +1,130 @@
+use convex::UserIdentityAttributes;
+use pyo3::{
+    exceptions::PyTypeError,
+    prelude::*,
+    types::{
+        PyBool,
+        PyDict,
+        PyString,
+    },
+};
+
+fn string_attr(key: &str, value: &PyAny) -> PyResult<String> {
+    if !value.is_instance_of::<PyString>() {
+        return Err(PyTypeError::new_err(format!(
+            "acting_as[{key:?}] must be a str, found {}",
+            value.get_type().name()?
+        )));
+    }
+    value.extract()
+}
+
+fn bool_attr(key: &str, value: &PyAny) -> PyResult<bool> {
+    if !value.is_instance_of::<PyBool>() {
+        return Err(PyTypeError::new_err(format!(
+            "acting_as[{key:?}] must be a bool, found {}",
+            value.get_type().name()?
+        )));
+    }
+    value.extract()
+}
+
+/// Convert a dict of identity attributes, keyed like the `UserIdentity`
+/// returned by `ctx.auth.getUserIdentity()` in Convex functions, to the
+/// identity an admin acts as.
+///
+/// Keys which aren't standard attributes are custom claims, with values
+/// other than strings stored as JSON. When `tokenIdentifier` is missing it is
+/// derived from `issuer` and `subject` like the deployment does.
+pub fn py_to_identity(py: Python<'_>, attrs: &PyDict) -> PyResult<UserIdentityAttributes> {
+    let mut identity = UserIdentityAttributes::default();
+    let mut token_identifier = None;
+    for (key, value) in attrs.iter() {
+        let key: &str = key.downcast::<PyString>()?.to_str()?;
+        match key {
+            "tokenIdentifier" => token_identifier = Some(string_attr(key, value)?),
+            "issuer" => identity.issuer = Some(string_attr(key, value)?),
+            "subject" => identity.subject = Some(string_attr(key, value)?),
+            "name" => identity.name = Some(string_attr(key, value)?),
+            "givenName" => identity.given_name = Some(string_attr(key, value)?),
+            "familyName" => identity.family_name = Some(string_attr(key, value)?),
+            "nickname" => identity.nickname = Some(string_attr(key, value)?),
+            "preferredUsername" => identity.preferred_username = Some(string_attr(key, value)?),
+            "profileUrl" => identity.profile_url = Some(string_attr(key, value)?),
+            "pictureUrl" => identity.picture_url = Some(string_attr(key, value)?),
+            "email" => identity.email = Some(string_attr(key, value)?),
+            "emailVerified" => identity.email_verified = Some(bool_attr(key, value)?),
+            "gender" => identity.gender = Some(string_attr(key, value)?),
+            "birthday" => identity.birthday = Some(string_attr(key, value)?),
+            "timezone" => identity.timezone = Some(string_attr(key, value)?),
+            "language" => identity.language = Some(string_attr(key, value)?),
+            "phoneNumber" => identity.phone_number = Some(string_attr(key, value)?),
+            "phoneNumberVerified" => identity.phone_number_verified = Some(bool_attr(key, value)?),
+            "address" => identity.address = Some(string_attr(key, value)?),
+            "updatedAt" => identity.updated_at = Some(string_attr(key, value)?),
+            _ => {
+                let claim = if value.is_instance_of::<PyString>() {
+                    value.extract()?
+                } else {
+                    py.import("json")?
+                        .call_method1("dumps", (value,))?
+                        .extract()?
+                };
+                identity.custom_claims.insert(key.to_string(), claim);
+            },
+        }
+    }
+    identity.token_identifier = match token_identifier {
+        Some(token_identifier) => token_identifier,
+        None => format!(
+            "{}|{}",
+            identity.issuer.as_deref().unwrap_or_default(),
+            identity.subject.as_deref().unwrap_or_default()
+        ),
+    };
+    Ok(identity)
+}
+
+/// Convert `identity` back to a dict keyed like the one it was converted from,
+/// with custom claims as they were stored.
+pub fn identity_to_py<'py>(
+    py: Python<'py>,
+    identity: &UserIdentityAttributes,
+) -> PyResult<&'py PyDict> {
+    let attrs = PyDict::new(py);
+    attrs.set_item("tokenIdentifier", &identity.token_identifier)?;
+    let optional_attrs = [
+        ("issuer", &identity.issuer),
+        ("subject", &identity.subject),
+        ("name", &identity.name),
+        ("givenName", &identity.given_name),
+        ("familyName", &identity.family_name),
+        ("nickname", &identity.nickname),
+        ("preferredUsername", &identity.preferred_username),
+        ("profileUrl", &identity.profile_url),
+        ("pictureUrl", &identity.picture_url),
+        ("email", &identity.email),
+        ("gender", &identity.gender),
+        ("birthday", &identity.birthday),
+        ("timezone", &identity.timezone),
+        ("language", &identity.language),
+        ("phoneNumber", &identity.phone_number),
+        ("address", &identity.address),
+        ("updatedAt", &identity.updated_at),
+    ];
+    for (key, value) in optional_attrs {
+        if let Some(value) = value {
+            attrs.set_item(key, value)?;
+        }
+    }
+    if let Some(email_verified) = identity.email_verified {
+        attrs.set_item("emailVerified", email_verified)?;
+    }
+    if let Some(phone_number_verified) = identity.phone_number_verified {
+        attrs.set_item("phoneNumberVerified", phone_number_verified)?;
+    }
+    for (key, claim) in &identity.custom_claims {
+        attrs.set_item(key, claim)?;
+    }
+    Ok(attrs)
+}
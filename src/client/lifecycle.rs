@@ -0,0 +1,258 @@
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        OnceLock,
+    },
+};
+
+use futures::future;
+use pyo3::{
+    exceptions::{
+        PyRuntimeError,
+        PyValueError,
+    },
+    pyfunction,
+    IntoPy,
+    PyAny,
+    PyErr,
+    PyObject,
+    PyResult,
+    Python,
+};
+use tokio::{
+    runtime::{
+        self,
+        Handle,
+        Runtime,
+    },
+    sync::watch,
+    time::{
+        sleep_until,
+        Duration,
+        Instant,
+    },
+};
+
+use crate::{
+    blocking,
+    fork::OwningProcess,
+};
+
+/// How long closing a client waits for in-flight work by default.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn closed_error() -> PyErr {
+    PyRuntimeError::new_err("PyConvexClient is closed")
+}
+
+/// Tracks mutations which haven't completed yet so closing a client can wait
+/// for them.
+#[derive(Clone)]
+pub struct InFlightMutations(Arc<watch::Sender<usize>>);
+
+impl InFlightMutations {
+    pub fn new() -> Self {
+        InFlightMutations(Arc::new(watch::channel(0).0))
+    }
+
+    /// Marks a mutation as in flight until the returned guard is dropped.
+    pub fn start(&self) -> InFlightGuard {
+        self.0.send_modify(|n| *n += 1);
+        InFlightGuard(self.0.clone())
+    }
+
+    /// Resolves once no mutations are in flight.
+    pub async fn drained(&self) {
+        let mut rx = self.0.subscribe();
+        let _ = rx.wait_for(|n| *n == 0).await;
+    }
+}
+
+pub struct InFlightGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+/// Tells calls in progress that their client is closing, so that they stop
+/// instead of keeping its connection and runtime alive.
+#[derive(Clone)]
+pub struct CloseSignal(Arc<watch::Sender<Option<Instant>>>);
+
+impl CloseSignal {
+    pub fn new() -> Self {
+        CloseSignal(Arc::new(watch::channel(None).0))
+    }
+
+    /// Signals that the client is closing. Mutations and actions which are
+    /// already running may complete until `deadline`.
+    pub fn close(&self, deadline: Instant) {
+        self.0.send_replace(Some(deadline));
+    }
+
+    async fn deadline(&self) -> Instant {
+        let mut rx = self.0.subscribe();
+        let deadline = rx
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|deadline| *deadline);
+        match deadline {
+            Some(deadline) => deadline,
+            None => future::pending().await,
+        }
+    }
+
+    /// Resolves to an error once the client is closing.
+    pub async fn closed(&self) -> PyErr {
+        self.deadline().await;
+        closed_error()
+    }
+
+    /// Resolves to an error once the client is closing and the deadline for
+    /// running mutations and actions has passed.
+    pub async fn deadline_passed(&self) -> PyErr {
+        sleep_until(self.deadline().await).await;
+        closed_error()
+    }
+}
+
+/// Set once a client or an awaitable uses the shared runtime, after which it
+/// can't be configured anymore.
+static SHARED_RUNTIME_OWNER: OnceLock<OwningProcess> = OnceLock::new();
+
+/// Configure the runtime shared by clients created with `shared_runtime=True`
+/// and by awaitables to run `worker_threads` worker threads. It otherwise runs
+/// one per CPU core.
+///
+/// Must be called before any client uses the shared runtime and before any
+/// awaitable is created, which start it. Raises a `RuntimeError` otherwise.
+#[pyfunction]
+pub fn configure_shared_runtime(worker_threads: usize) -> PyResult<()> {
+    if worker_threads == 0 {
+        return Err(PyValueError::new_err("worker_threads must be at least 1"));
+    }
+    if SHARED_RUNTIME_OWNER.get().is_some() {
+        return Err(PyRuntimeError::new_err(
+            "The shared runtime is already running",
+        ));
+    }
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all().worker_threads(worker_threads);
+    pyo3_asyncio::tokio::init(builder);
+    Ok(())
+}
+
+/// Returns an awaitable resolving to the output of `future`, which runs on the
+/// shared runtime.
+///
+/// The shared runtime is pyo3_asyncio's, which starts it on first use, so
+/// awaitables must be created with this rather than with pyo3_asyncio directly
+/// for `configure_shared_runtime` to know that it's too late.
+pub fn future_into_py<F, T>(py: Python<'_>, future: F) -> PyResult<&PyAny>
+where
+    F: Future<Output = PyResult<T>> + Send + 'static,
+    T: IntoPy<PyObject>,
+{
+    check_shared_runtime()?;
+    pyo3_asyncio::tokio::future_into_py(py, future)
+}
+
+/// Marks the shared runtime as started, raising an error in a child forked
+/// after it was started, where it has no worker threads left.
+fn check_shared_runtime() -> PyResult<()> {
+    if SHARED_RUNTIME_OWNER
+        .get_or_init(OwningProcess::current)
+        .is_current()
+    {
+        return Ok(());
+    }
+    Err(PyRuntimeError::new_err(
+        "The shared runtime was started before the process forked and can't be used in the \
+         child process, so neither can awaitables nor clients created with \
+         shared_runtime=True.",
+    ))
+}
+
+/// The runtime of a client, shared by the calls made with the client.
+pub enum ClientRuntime {
+    /// A runtime for this client alone.
+    ///
+    /// Closing the client shuts the runtime down, unless calls made from other
+    /// threads are still using it. Those stop as the client is closing, and
+    /// the last of them shuts it down.
+    Owned(Option<Runtime>),
+    /// The process-wide runtime, which is never shut down.
+    Shared(&'static Runtime),
+}
+
+impl ClientRuntime {
+    pub fn owned(rt: Runtime) -> Self {
+        ClientRuntime::Owned(Some(rt))
+    }
+
+    pub fn shared() -> PyResult<Self> {
+        check_shared_runtime()?;
+        Ok(ClientRuntime::Shared(pyo3_asyncio::tokio::get_runtime()))
+    }
+
+    fn runtime(&self) -> &Runtime {
+        match self {
+            ClientRuntime::Owned(rt) => rt
+                .as_ref()
+                .expect("The runtime is only taken when shutting down"),
+            ClientRuntime::Shared(rt) => rt,
+        }
+    }
+
+    pub fn handle(&self) -> &Handle {
+        self.runtime().handle()
+    }
+
+    /// Block the current thread on `future`. See `blocking::block_on`.
+    pub fn block_on<F, T>(&self, offload: bool, future: F) -> PyResult<T>
+    where
+        F: Future<Output = PyResult<T>> + Send,
+        T: Send,
+    {
+        blocking::block_on(self.handle(), offload, future)
+    }
+
+    /// Shut down an owned runtime, waiting up to `timeout` for its tasks to
+    /// stop, if nothing else is using it.
+    pub fn shutdown(self: Arc<Self>, timeout: Duration) {
+        if let Ok(mut this) = Arc::try_unwrap(self) {
+            if let ClientRuntime::Owned(rt) = &mut this {
+                if let Some(rt) = rt.take() {
+                    shutdown_runtime(rt, timeout);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ClientRuntime {
+    fn drop(&mut self) {
+        // This may run on any thread, including with the GIL held, so don't
+        // wait for tasks which may be waiting for it.
+        if let ClientRuntime::Owned(rt) = self {
+            if let Some(rt) = rt.take() {
+                rt.shutdown_background();
+            }
+        }
+    }
+}
+
+/// Shut down `rt`, waiting up to `timeout` for its tasks to stop.
+fn shutdown_runtime(rt: Runtime, timeout: Duration) {
+    // Waiting for a runtime to shut down isn't allowed from within an async
+    // context and panics, just like dropping it there does.
+    if Handle::try_current().is_ok() {
+        rt.shutdown_background();
+    } else {
+        rt.shutdown_timeout(timeout);
+    }
+}
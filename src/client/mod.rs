@@ -1,31 +1,49 @@
+mod auth;
+mod auth_state;
+mod deployment_url;
+mod environment;
+mod identity;
+mod lifecycle;
+
 use std::{
     collections::BTreeMap,
+    future::Future,
     io::{
         self,
         Write,
     },
-    ops::Deref,
+    mem,
+    path::PathBuf,
+    sync::Arc,
 };
 
 use convex::{
     ConvexClient,
-    FunctionResult,
     Value,
 };
+use futures::future;
+use parking_lot::Mutex;
 use pyo3::{
-    exceptions::PyException,
+    exceptions::{
+        PyException,
+        PyValueError,
+    },
     prelude::*,
     pyclass,
     types::{
         PyDict,
+        PyMapping,
         PyString,
     },
 };
 use tokio::{
     runtime,
+    task::JoinHandle,
     time::{
         sleep,
+        timeout_at,
         Duration,
+        Instant,
     },
 };
 use tracing::{
@@ -45,78 +63,251 @@ use tracing_subscriber::{
     Registry,
 };
 
+pub use self::lifecycle::future_into_py;
+use self::{
+    auth::{
+        spawn_refresher,
+        validate_admin_key,
+        validate_token,
+    },
+    auth_state::{
+        AuthStateTracker,
+        PyAuthState,
+    },
+    deployment_url::validate_deployment_url,
+    environment::EnvConfig,
+    identity::{
+        identity_to_py,
+        py_to_identity,
+    },
+    lifecycle::{
+        closed_error,
+        configure_shared_runtime,
+        ClientRuntime,
+        CloseSignal,
+        InFlightMutations,
+        CLOSE_TIMEOUT,
+    },
+};
 use crate::{
+    blocking::set_signal_check_interval,
+    errors::ConvexTimeoutError,
+    fork::OwningProcess,
     query_result::{
-        convex_error_to_py_wrapped,
+        conversion_error,
+        function_result_to_py_result,
+        mapping_to_object,
         py_to_value,
         value_to_py,
-        value_to_py_wrapped,
+        IntPolicy,
+        Path,
     },
     subscription::{
         PyQuerySetSubscription,
         PyQuerySubscription,
+        WeakSubscription,
     },
 };
 
-// TODO can this be removed?
-struct BTreeMapWrapper<String, Value>(BTreeMap<String, Value>);
-impl BTreeMapWrapper<String, Value> {
-    fn from(py: Python<'_>, d: &PyDict) -> Self {
-        let map = d
-            .iter()
-            .filter_map(|(key, value)| {
-                let k: Result<&pyo3::types::PyString, _> = key.extract();
-                let v: Result<Value, _> = py_to_value(py, value);
-                if let (Ok(k), Ok(v)) = (k, v) {
-                    Some((k.to_string(), v))
-                } else {
-                    // TODO this seems wrong, shouldn't we raise here?
-                    None
-                }
-            })
-            .collect();
-
-        BTreeMapWrapper(map)
+/// Convert the arguments of a function call, which can be any mapping,
+/// raising a `ConvexConversionError` locating the first value which can't be
+/// converted rather than leaving it out.
+fn py_to_args(
+    py: Python<'_>,
+    args: Option<&PyAny>,
+    int_policy: IntPolicy,
+) -> PyResult<BTreeMap<String, Value>> {
+    let root = Path::Root("args");
+    let Some(args) = args else {
+        return Ok(BTreeMap::new());
+    };
+    if args.downcast::<PyMapping>().is_err() {
+        return Err(conversion_error(
+            &root,
+            &format!(
+                "Args must be a mapping, found {} of type {}",
+                args.repr()?,
+                args.get_type().name()?
+            ),
+        ));
     }
+    mapping_to_object(py, args, &root, "Argument names", int_policy)
 }
 
-impl<K, V> Deref for BTreeMapWrapper<K, V> {
-    type Target = BTreeMap<K, V>;
+fn duration_from_secs(secs: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| PyValueError::new_err(format!("Invalid timeout: {secs}")))
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+/// Resolves to a timeout error once `timeout` has elapsed, or never resolves
+/// if there is no timeout.
+async fn timeout_after(timeout: Option<Duration>) -> PyErr {
+    match timeout {
+        Some(timeout) => {
+            sleep(timeout).await;
+            ConvexTimeoutError::new_err(format!(
+                "Timed out after {} seconds",
+                timeout.as_secs_f64()
+            ))
+        },
+        None => future::pending().await,
     }
 }
 
-async fn check_python_signals_periodically() -> PyResult<()> {
-    loop {
-        sleep(Duration::from_secs(1)).await;
-        Python::with_gil(|py| py.check_signals())?;
-    }
+/// Runs a mutation or action in a task of its own on `rt`, resolving to its
+/// result. Unlike the call waiting for it, the task isn't dropped when the call
+/// times out, is interrupted or its awaitable is cancelled, so the mutation or
+/// action completes in the background rather than being abandoned in flight.
+///
+/// Once the client is closing, this resolves to an error right away, while the
+/// task runs until the deadline given to close the client.
+async fn run_detached<T>(
+    rt: &runtime::Handle,
+    closing: &CloseSignal,
+    call: impl Future<Output = PyResult<T>> + Send + 'static,
+) -> PyResult<T>
+where
+    T: Send + 'static,
+{
+    let deadline = closing.clone();
+    let task = rt.spawn(async move {
+        tokio::select!(
+            res = call => res,
+            err = deadline.deadline_passed() => Err(err),
+        )
+    });
+    tokio::select!(
+        res = task => res.unwrap_or_else(|e| Err(PyException::new_err(e.to_string()))),
+        err = closing.closed() => Err(err),
+    )
 }
+
 /// An asynchronous client to interact with a specific project to perform
 /// queries/mutations/actions and manage query subscriptions.
-#[pyclass]
+///
+/// The client is a handle which can be shared by several Python threads: calls
+/// made from different threads run concurrently over the same connection.
+#[pyclass(frozen)]
 pub struct PyConvexClient {
-    rt: tokio::runtime::Runtime,
+    /// `None` once the client has been closed.
+    open: Mutex<Option<OpenClient>>,
+    /// The client can't be used in processes forked from this one.
+    owner: OwningProcess,
+    /// Timeout applied to calls which don't specify their own.
+    timeout: Option<Duration>,
+    /// Whether blocking calls made from async code are made from a separate
+    /// thread rather than raising an error.
+    offload_nested_calls: bool,
+    /// How ints in function arguments which don't fit in a float are
+    /// converted.
+    int_policy: IntPolicy,
+    /// Whether `Int64` results are decoded as plain ints rather than
+    /// `ConvexInt64`s.
+    int64_as_int: bool,
+    in_flight: InFlightMutations,
+    /// Stops calls in progress when the client is closed.
+    closing: CloseSignal,
+    /// Subscriptions to unsubscribe when the client is closed.
+    subscriptions: Mutex<Vec<WeakSubscription>>,
+    /// Refreshes auth set with a token fetching callback.
+    ///
+    /// The lock also serializes auth changes. Changing auth checks for signals,
+    /// which needs the GIL, so it must only be locked while the GIL is
+    /// released.
+    auth_refresher: Mutex<Option<JoinHandle<()>>>,
+    auth_state: AuthStateTracker,
+}
+
+/// What calls need from an open client. Calls clone it so that no lock is
+/// held while they wait.
+#[derive(Clone)]
+struct OpenClient {
+    rt: Arc<ClientRuntime>,
     client: ConvexClient,
 }
 
 impl PyConvexClient {
-    fn function_result_to_py_result(
-        &mut self,
+    fn resolve_timeout(&self, timeout: Option<f64>) -> PyResult<Option<Duration>> {
+        match timeout {
+            Some(secs) => Ok(Some(duration_from_secs(secs)?)),
+            None => Ok(self.timeout),
+        }
+    }
+
+    fn open(&self) -> PyResult<OpenClient> {
+        self.owner.check("PyConvexClient")?;
+        self.open.lock().clone().ok_or_else(closed_error)
+    }
+
+    fn track_subscription(&self, subscription: WeakSubscription) {
+        let mut subscriptions = self.subscriptions.lock();
+        subscriptions.retain(|s| s.is_alive());
+        subscriptions.push(subscription);
+    }
+
+    /// Set `token` as the auth of the client, replacing any previous auth.
+    /// With `fetch_token`, the token is then kept fresh by a refresher.
+    fn set_auth_blocking(
+        &self,
         py: Python<'_>,
-        result: FunctionResult,
-    ) -> PyResult<PyObject> {
-        match result {
-            FunctionResult::Value(v) => Ok(value_to_py_wrapped(py, v)),
-            FunctionResult::ErrorMessage(e) => Err(PyException::new_err(e)),
-            FunctionResult::ConvexError(v) => {
-                // pyo3 can't defined new custom exceptions when using the common abi
-                // `features = ["abi3"]` https://github.com/PyO3/pyo3/issues/1344
-                // so we define this error in Python. So just return a wrapped one.
-                Ok(convex_error_to_py_wrapped(py, v))
-            },
+        token: Option<String>,
+        fetch_token: Option<PyObject>,
+    ) -> PyResult<()> {
+        if let Some(Err(e)) = token.as_deref().map(validate_token) {
+            return Err(e);
+        }
+        let OpenClient { rt, mut client } = self.open()?;
+        py.allow_threads(|| {
+            let mut refresher = self.auth_refresher.lock();
+            if let Some(refresher) = refresher.take() {
+                refresher.abort();
+            }
+            self.auth_state.set_token(rt.handle(), token.as_deref());
+            rt.block_on(self.offload_nested_calls, async {
+                client.set_auth(token.clone()).await;
+                Ok(())
+            })?;
+            if let Some(fetch_token) = fetch_token {
+                *refresher = Some(spawn_refresher(
+                    rt.handle(),
+                    client,
+                    fetch_token,
+                    token,
+                    self.auth_state.clone(),
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    fn unsubscribe_all(&self) {
+        for subscription in self.subscriptions.lock().drain(..) {
+            subscription.unsubscribe();
+        }
+    }
+}
+
+impl Drop for PyConvexClient {
+    fn drop(&mut self) {
+        let refresher = self.auth_refresher.get_mut().take();
+        if !self.owner.is_current() {
+            // Shutting the runtime down, or even stopping its tasks, may hang
+            // in a forked child, so leak them instead.
+            mem::forget((self.open.get_mut().take(), refresher));
+            return;
+        }
+        // Background tasks aren't stopped with the shared runtime.
+        if let Some(refresher) = refresher {
+            refresher.abort();
+        }
+        // Stop awaitables still using the client, without waiting for
+        // in-flight mutations like closing does.
+        self.closing.close(Instant::now());
+        if let Some(OpenClient { rt, client }) = self.open.get_mut().take() {
+            drop(client);
+            // Tasks stopping with the runtime may be waiting for the GIL, which
+            // is held while the client is dropped.
+            Python::with_gil(|py| py.allow_threads(|| rt.shutdown(CLOSE_TIMEOUT)));
         }
     }
 }
@@ -124,178 +315,542 @@ impl PyConvexClient {
 #[pymethods]
 impl PyConvexClient {
     /// Note that the WebSocket is not connected yet and therefore the
-    /// connection url is not validated to be accepting connections.
+    /// connection url is not validated to be accepting connections. Raises a
+    /// `ValueError` if it isn't a well-formed deployment URL though.
+    ///
+    /// `timeout` is a default, in seconds, for calls and awaitables which don't
+    /// specify their own. With `shared_runtime`, the client runs on the runtime
+    /// shared by every such client and by awaitables instead of its own.
+    ///
+    /// Blocking calls made from async code, such as callbacks run by the
+    /// client, raise an error, unless `offload_nested_calls` is set to make
+    /// them from a separate thread.
+    ///
+    /// `int_policy` is how ints in function arguments which don't fit in a
+    /// float are converted: `"strict"` raises, `"float"` rounds them and
+    /// `"int64"` converts them to `Int64`s. With `int64_as_int`, `Int64`
+    /// results are decoded as plain ints instead of `ConvexInt64`s, so they are
+    /// sent back as floats rather than `Int64`s.
     #[new]
-    fn py_new(deployment_url: &PyString) -> PyResult<Self> {
+    #[pyo3(signature = (
+        deployment_url,
+        timeout=None,
+        shared_runtime=false,
+        offload_nested_calls=false,
+        int_policy=IntPolicy::Strict,
+        int64_as_int=false
+    ))]
+    fn py_new(
+        deployment_url: &PyString,
+        timeout: Option<f64>,
+        shared_runtime: bool,
+        offload_nested_calls: bool,
+        int_policy: IntPolicy,
+        int64_as_int: bool,
+    ) -> PyResult<Self> {
         let dep = deployment_url.to_str()?;
+        validate_deployment_url(dep)?;
+        let timeout = timeout.map(duration_from_secs).transpose()?;
         // The ConvexClient is instantiated in the context of a tokio Runtime, and
         // needs to run its worker in the background so that it can constantly
         // listen for new messages from the server. Here, we choose to build a
         // multi-thread scheduler to make that possible.
-        let rt = runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(1)
-            .build()
-            .unwrap();
+        let rt = if shared_runtime {
+            ClientRuntime::shared()?
+        } else {
+            ClientRuntime::owned(
+                runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .worker_threads(1)
+                    .build()
+                    .unwrap(),
+            )
+        };
 
         // Block on the async function using the Tokio runtime.
-        let instance = rt.block_on(ConvexClient::new(dep));
-        match instance {
-            Ok(instance) => Ok(PyConvexClient {
-                rt,
+        let instance = rt.block_on(offload_nested_calls, async {
+            ConvexClient::new(dep).await.map_err(|e| {
+                PyException::new_err(format!(
+                    "{}: {}",
+                    "Failed to create PyConvexClient",
+                    &e.to_string()
+                ))
+            })
+        })?;
+        Ok(PyConvexClient {
+            open: Mutex::new(Some(OpenClient {
+                rt: Arc::new(rt),
                 client: instance,
-            }),
-            Err(e) => Err(PyException::new_err(format!(
-                "{}: {}",
-                "Failed to create PyConvexClient",
-                &e.to_string()
-            ))),
+            })),
+            owner: OwningProcess::current(),
+            timeout,
+            offload_nested_calls,
+            int_policy,
+            int64_as_int,
+            in_flight: InFlightMutations::new(),
+            closing: CloseSignal::new(),
+            subscriptions: Mutex::new(Vec::new()),
+            auth_refresher: Mutex::new(None),
+            auth_state: AuthStateTracker::new(),
+        })
+    }
+
+    /// Creates a client for the deployment configured in the environment, or
+    /// in `env_file`, like the `.env.local` file written by the Convex CLI.
+    ///
+    /// The deployment URL is taken from `CONVEX_URL`, or resolved from the
+    /// deployment named by `CONVEX_DEPLOY_KEY` or `CONVEX_DEPLOYMENT`.
+    /// `CONVEX_DEPLOY_KEY` is also set as admin auth, and is required with
+    /// `require_admin_key`. Raises a `ValueError` naming the variables which
+    /// were looked for if they aren't set.
+    ///
+    /// Other keyword arguments are passed to the constructor.
+    #[staticmethod]
+    #[pyo3(signature = (env_file=PathBuf::from(".env.local"), require_admin_key=false, **kwargs))]
+    fn from_env(
+        py: Python<'_>,
+        env_file: PathBuf,
+        require_admin_key: bool,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<Py<Self>> {
+        let config = EnvConfig::resolve(&env_file, require_admin_key)?;
+        let client: Py<Self> = py
+            .get_type::<Self>()
+            .call((config.deployment_url,), kwargs)?
+            .extract()?;
+        if let Some(admin_key) = config.admin_key {
+            let admin_key = PyString::new(py, &admin_key);
+            client.borrow(py).set_admin_auth(py, admin_key, None)?;
         }
+        Ok(client)
     }
 
     /// Creates a single subscription to a query, with optional args.
+    ///
+    /// `int64_as_int` overrides whether the subscription decodes `Int64`
+    /// results as plain ints, which defaults to the setting of the client.
+    #[pyo3(signature = (name, args=None, timeout=None, int64_as_int=None))]
     pub fn subscribe(
-        &mut self,
+        &self,
         py: Python<'_>,
         name: &PyString,
-        args: Option<&PyDict>,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
+        int64_as_int: Option<bool>,
     ) -> PyResult<PyQuerySubscription> {
         let name: &str = name.to_str()?;
-        let args: BTreeMapWrapper<String, Value> =
-            BTreeMapWrapper::from(py, args.unwrap_or(PyDict::new(py)));
-        let args: BTreeMap<String, Value> = args.deref().clone();
-
-        let res = self.rt.block_on(async {
-            tokio::select!(
-                res1 = self.client.subscribe(name, args) => res1,
-                res2 = check_python_signals_periodically() => Err(res2.expect_err("Panic!").into())
-            )
-        });
-        match res {
-            Ok(res) => {
-                let mut py_res: PyQuerySubscription = res.into();
-                py_res.rt_handle = Some(self.rt.handle().clone());
-                Ok(py_res)
-            },
-            Err(e) => Err(PyException::new_err(e.to_string())),
-        }
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+        let OpenClient { rt, mut client } = self.open()?;
+
+        let res = py.allow_threads(|| {
+            rt.block_on(self.offload_nested_calls, async {
+                tokio::select!(
+                    res1 = client.subscribe(name, args) => res1.map_err(|e| PyException::new_err(e.to_string())),
+                    err = timeout_after(timeout) => Err(err),
+                    err = self.closing.closed() => Err(err),
+                )
+            })
+        })?;
+        let mut py_res: PyQuerySubscription = res.into();
+        py_res.rt_handle = Some(rt.handle().clone());
+        py_res.offload_nested_calls = self.offload_nested_calls;
+        py_res.int64_as_int = int64_as_int.unwrap_or(self.int64_as_int);
+        self.track_subscription(py_res.downgrade());
+        Ok(py_res)
     }
 
     /// Make a oneshot request to a query `name` with `args`.
     ///
     /// Returns a `convex::Value` representing the result of the query.
+    #[pyo3(signature = (name, args=None, timeout=None))]
     pub fn query(
-        &mut self,
+        &self,
         py: Python<'_>,
         name: &PyString,
-        args: Option<&PyDict>,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
     ) -> PyResult<PyObject> {
         let name: &str = name.to_str()?;
-        let args: BTreeMapWrapper<String, Value> =
-            BTreeMapWrapper::from(py, args.unwrap_or(PyDict::new(py)));
-        let args: BTreeMap<String, Value> = args.deref().clone();
-
-        let res = self.rt.block_on(async {
-            tokio::select!(
-                res1 = self.client.query(name, args) => res1,
-                res2 = check_python_signals_periodically() => Err(res2.expect_err("Panic!").into())
-            )
-        });
-
-        match res {
-            Ok(res) => self.function_result_to_py_result(py, res),
-            Err(e) => Err(PyException::new_err(e.to_string())),
-        }
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+        let OpenClient { rt, mut client } = self.open()?;
+        let auth_state = &self.auth_state;
+
+        let res = py.allow_threads(|| {
+            rt.block_on(self.offload_nested_calls, async {
+                tokio::select!(
+                    res1 = client.query(name, args) => {
+                        auth_state.observe(&res1);
+                        res1.map_err(|e| PyException::new_err(e.to_string()))
+                    },
+                    err = timeout_after(timeout) => Err(err),
+                    err = self.closing.closed() => Err(err),
+                )
+            })
+        })?;
+        function_result_to_py_result(py, res, self.int64_as_int)
     }
 
     /// Perform a mutation `name` with `args` and return a future
     /// containing the return value of the mutation once it completes.
+    #[pyo3(signature = (name, args=None, timeout=None))]
     pub fn mutation(
-        &mut self,
+        &self,
         py: Python<'_>,
         name: &PyString,
-        args: Option<&PyDict>,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
     ) -> PyResult<PyObject> {
-        let name: &str = name.to_str()?;
-        let args: BTreeMapWrapper<String, Value> =
-            BTreeMapWrapper::from(py, args.unwrap_or(PyDict::new(py)));
-        let args: BTreeMap<String, Value> = args.deref().clone();
-
-        let res = self.rt.block_on(async {
-            tokio::select!(
-                res1 = self.client.mutation(name, args) => res1,
-                res2 = check_python_signals_periodically() => Err(res2.expect_err("Panic!").into())
-            )
-        });
-
-        match res {
-            Ok(res) => self.function_result_to_py_result(py, res),
-            Err(e) => Err(PyException::new_err(e.to_string())),
-        }
+        let name = name.to_str()?.to_string();
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+        let OpenClient { rt, mut client } = self.open()?;
+        let auth_state = self.auth_state.clone();
+
+        let in_flight = self.in_flight.start();
+        let mutation = async move {
+            let res = client.mutation(&name, args).await;
+            auth_state.observe(&res);
+            drop(in_flight);
+            res.map_err(|e| PyException::new_err(e.to_string()))
+        };
+        let res = py.allow_threads(|| {
+            rt.block_on(self.offload_nested_calls, async {
+                tokio::select!(
+                    res1 = run_detached(rt.handle(), &self.closing, mutation) => res1,
+                    err = timeout_after(timeout) => Err(err),
+                )
+            })
+        })?;
+        function_result_to_py_result(py, res, self.int64_as_int)
     }
 
     /// Perform an action `name` with `args` and return a future
     /// containing the return value of the action once it completes.
+    #[pyo3(signature = (name, args=None, timeout=None))]
     pub fn action(
-        &mut self,
+        &self,
         py: Python<'_>,
         name: &PyString,
-        args: Option<&PyDict>,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
     ) -> PyResult<PyObject> {
-        let name: &str = name.to_str()?;
-        let args: BTreeMapWrapper<String, Value> =
-            BTreeMapWrapper::from(py, args.unwrap_or(PyDict::new(py)));
-        let args: BTreeMap<String, Value> = args.deref().clone();
-
-        let res = self.rt.block_on(async {
-            tokio::select!(
-                res1 = self.client.action(name, args) => res1,
-                res2 = check_python_signals_periodically() => Err(res2.expect_err("Panic!").into())
-            )
-        });
+        let name = name.to_str()?.to_string();
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+        let OpenClient { rt, mut client } = self.open()?;
+        let auth_state = self.auth_state.clone();
+
+        let action = async move {
+            let res = client.action(&name, args).await;
+            auth_state.observe(&res);
+            res.map_err(|e| PyException::new_err(e.to_string()))
+        };
+        let res = py.allow_threads(|| {
+            rt.block_on(self.offload_nested_calls, async {
+                tokio::select!(
+                    res1 = run_detached(rt.handle(), &self.closing, action) => res1,
+                    err = timeout_after(timeout) => Err(err),
+                )
+            })
+        })?;
+        function_result_to_py_result(py, res, self.int64_as_int)
+    }
 
-        match res {
-            Ok(res) => self.function_result_to_py_result(py, res),
-            Err(e) => Err(PyException::new_err(e.to_string())),
-        }
+    /// Make a oneshot request to a query `name` with `args` and return an
+    /// awaitable resolving to the result of the query.
+    ///
+    /// Unlike `query`, this does not block the calling thread. The awaitable
+    /// raises a `ConvexTimeoutError` after `timeout` seconds, which defaults to
+    /// the timeout of the client.
+    #[pyo3(signature = (name, args=None, timeout=None))]
+    pub fn aquery<'p>(
+        &self,
+        py: Python<'p>,
+        name: &PyString,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
+    ) -> PyResult<&'p PyAny> {
+        let name: String = name.to_str()?.to_string();
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+
+        let mut client = self.open()?.client;
+        let auth_state = self.auth_state.clone();
+        let closing = self.closing.clone();
+        let int64_as_int = self.int64_as_int;
+        future_into_py(py, async move {
+            let res = tokio::select!(
+                res1 = client.query(&name, args) => {
+                    auth_state.observe(&res1);
+                    res1.map_err(|e| PyException::new_err(e.to_string()))
+                },
+                err = timeout_after(timeout) => Err(err),
+                err = closing.closed() => Err(err),
+            );
+            Python::with_gil(|py| function_result_to_py_result(py, res?, int64_as_int))
+        })
+    }
+
+    /// Perform a mutation `name` with `args` and return an awaitable
+    /// resolving to the return value of the mutation once it completes.
+    #[pyo3(signature = (name, args=None, timeout=None))]
+    pub fn amutation<'p>(
+        &self,
+        py: Python<'p>,
+        name: &PyString,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
+    ) -> PyResult<&'p PyAny> {
+        let name: String = name.to_str()?.to_string();
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+
+        let OpenClient { rt, mut client } = self.open()?;
+        let rt = rt.handle().clone();
+        let auth_state = self.auth_state.clone();
+        let closing = self.closing.clone();
+        let int64_as_int = self.int64_as_int;
+
+        let in_flight = self.in_flight.start();
+        let mutation = async move {
+            let res = client.mutation(&name, args).await;
+            auth_state.observe(&res);
+            drop(in_flight);
+            res.map_err(|e| PyException::new_err(e.to_string()))
+        };
+        future_into_py(py, async move {
+            let res = tokio::select!(
+                res1 = run_detached(&rt, &closing, mutation) => res1,
+                err = timeout_after(timeout) => Err(err),
+            );
+            Python::with_gil(|py| function_result_to_py_result(py, res?, int64_as_int))
+        })
+    }
+
+    /// Perform an action `name` with `args` and return an awaitable
+    /// resolving to the return value of the action once it completes.
+    #[pyo3(signature = (name, args=None, timeout=None))]
+    pub fn aaction<'p>(
+        &self,
+        py: Python<'p>,
+        name: &PyString,
+        args: Option<&PyAny>,
+        timeout: Option<f64>,
+    ) -> PyResult<&'p PyAny> {
+        let name: String = name.to_str()?.to_string();
+        let args = py_to_args(py, args, self.int_policy)?;
+        let timeout = self.resolve_timeout(timeout)?;
+
+        let OpenClient { rt, mut client } = self.open()?;
+        let rt = rt.handle().clone();
+        let auth_state = self.auth_state.clone();
+        let closing = self.closing.clone();
+        let int64_as_int = self.int64_as_int;
+
+        let action = async move {
+            let res = client.action(&name, args).await;
+            auth_state.observe(&res);
+            res.map_err(|e| PyException::new_err(e.to_string()))
+        };
+        future_into_py(py, async move {
+            let res = tokio::select!(
+                res1 = run_detached(&rt, &closing, action) => res1,
+                err = timeout_after(timeout) => Err(err),
+            );
+            Python::with_gil(|py| function_result_to_py_result(py, res?, int64_as_int))
+        })
     }
 
     /// Get a consistent view of the results of every query the client is
     /// currently subscribed to. This set changes over time as subscriptions
     /// are added and dropped.
-    pub fn watch_all(&mut self, _py: Python<'_>) -> PyQuerySetSubscription {
-        let mut py_res: PyQuerySetSubscription = self.client.watch_all().into();
-        py_res.rt_handle = Some(self.rt.handle().clone());
-        py_res
+    ///
+    /// `int64_as_int` overrides whether `Int64` results are decoded as plain
+    /// ints, which defaults to the setting of the client.
+    #[pyo3(signature = (int64_as_int=None))]
+    pub fn watch_all(
+        &self,
+        _py: Python<'_>,
+        int64_as_int: Option<bool>,
+    ) -> PyResult<PyQuerySetSubscription> {
+        let OpenClient { rt, client } = self.open()?;
+        let mut py_res: PyQuerySetSubscription = client.watch_all().into();
+        py_res.rt_handle = Some(rt.handle().clone());
+        py_res.offload_nested_calls = self.offload_nested_calls;
+        py_res.int64_as_int = int64_as_int.unwrap_or(self.int64_as_int);
+        self.track_subscription(py_res.downgrade());
+        Ok(py_res)
     }
 
     /// Set auth for use when calling Convex functions.
     ///
     /// Set it with a token that you get from your auth provider via their login
     /// flow. If `None` is passed as the token, then auth is unset (logging
-    /// out).
-    pub fn set_auth(&mut self, token: Option<&PyString>) {
+    /// out). Raises a `ValueError` if the token isn't a well-formed JWT.
+    pub fn set_auth(&self, py: Python<'_>, token: Option<&PyString>) -> PyResult<()> {
         let token = token.map(|t| t.to_string());
-        self.rt.block_on(async {
-            tokio::select!(
-                res1 = self.client.set_auth(token) => res1,
-                _ = check_python_signals_periodically() => panic!()
-            )
-        });
+        self.set_auth_blocking(py, token, None)
+    }
+
+    /// Set auth with a callback which fetches tokens from your auth provider.
+    ///
+    /// `fetch_token` is called with a `force_refresh_token` keyword argument
+    /// and returns a token, or `None` to unset auth. It is called right away,
+    /// then again shortly before each token expires. It isn't called on
+    /// WebSocket reconnects or when a token is rejected, which the underlying
+    /// client doesn't report.
+    pub fn set_auth_callback(&self, py: Python<'_>, fetch_token: PyObject) -> PyResult<()> {
+        let token = auth::fetch_token(py, &fetch_token, false)?;
+        self.set_auth_blocking(py, token, Some(fetch_token))
     }
 
     /// Set auth which allows access to system resources.
     ///
     /// Set it with a deploy key obtained from the convex dashboard of a
-    /// deployment you control. This auth cannot be unset.
-    pub fn set_admin_auth(&mut self, token: &PyString) {
+    /// deployment you control. This auth cannot be unset. Raises a
+    /// `ValueError` if the key is malformed.
+    ///
+    /// Pass a dict of identity attributes as `acting_as` to call functions as
+    /// that user would, e.g. `{"subject": "user123", "issuer": "https://..."}`.
+    #[pyo3(signature = (token, acting_as=None))]
+    pub fn set_admin_auth(
+        &self,
+        py: Python<'_>,
+        token: &PyString,
+        acting_as: Option<&PyDict>,
+    ) -> PyResult<()> {
         let token = token.to_string();
-        self.rt.block_on(async {
-            tokio::select!(
-                res1 = self.client.set_admin_auth(token, None) => res1,
-                _ = check_python_signals_periodically() => panic!()
-            )
+        validate_admin_key(&token)?;
+        let acting_as = acting_as
+            .map(|attrs| py_to_identity(py, attrs))
+            .transpose()?;
+        let OpenClient { rt, mut client } = self.open()?;
+        py.allow_threads(|| {
+            let mut refresher = self.auth_refresher.lock();
+            if let Some(refresher) = refresher.take() {
+                refresher.abort();
+            }
+            self.auth_state.set_admin_key();
+            rt.block_on(self.offload_nested_calls, async {
+                client.set_admin_auth(token, acting_as).await;
+                Ok(())
+            })
+        })
+    }
+
+    /// The state of the auth set on this client.
+    #[getter]
+    pub fn auth_state(&self) -> PyAuthState {
+        self.auth_state.state()
+    }
+
+    /// Call `callback` with the new auth state whenever it changes.
+    pub fn on_auth_state_change(&self, callback: PyObject) -> PyResult<()> {
+        let rt = self.open()?.rt;
+        self.auth_state.on_change(rt.handle(), callback);
+        Ok(())
+    }
+
+    /// Close the client.
+    ///
+    /// Unsubscribes every subscription made by this client and waits up to
+    /// `timeout` seconds for in-flight mutations to complete before closing
+    /// the WebSocket and shutting down the runtime. Other calls still waiting,
+    /// like those made from other threads, raise a `RuntimeError` right away.
+    /// Closing a client which is already closed does nothing.
+    #[pyo3(signature = (timeout=None))]
+    pub fn close(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<()> {
+        let timeout = timeout.map(duration_from_secs).transpose()?;
+        let deadline = Instant::now() + timeout.unwrap_or(CLOSE_TIMEOUT);
+        let Some(OpenClient { rt, client }) = self.open.lock().take() else {
+            return Ok(());
+        };
+        if !self.owner.is_current() {
+            // The runtime can't be shut down in a forked child.
+            mem::forget((rt, client));
+            return Ok(());
+        }
+        self.closing.close(deadline);
+        self.unsubscribe_all();
+
+        py.allow_threads(|| {
+            if let Some(refresher) = self.auth_refresher.lock().take() {
+                refresher.abort();
+            }
+            // Unless offloaded, blocking isn't allowed from within an async
+            // context, where `aclose` should be used instead. Don't wait for
+            // in-flight mutations then.
+            let _ = rt.block_on(self.offload_nested_calls, async {
+                let _ = timeout_at(deadline, self.in_flight.drained()).await;
+                Ok(())
+            });
+            drop(client);
+            rt.shutdown(deadline.saturating_duration_since(Instant::now()));
         });
+        Ok(())
+    }
+
+    /// Close the client without blocking the event loop, returning an
+    /// awaitable which resolves once the client is closed. See `close`.
+    #[pyo3(signature = (timeout=None))]
+    pub fn aclose<'p>(&self, py: Python<'p>, timeout: Option<f64>) -> PyResult<&'p PyAny> {
+        let timeout = timeout.map(duration_from_secs).transpose()?;
+        let deadline = Instant::now() + timeout.unwrap_or(CLOSE_TIMEOUT);
+        let open = self.open.lock().take();
+        if let Err(e) = self.owner.check("PyConvexClient") {
+            // The runtime can't be shut down in a forked child, and awaitables
+            // may not be able to run there either.
+            mem::forget(open);
+            return Err(e);
+        }
+        if open.is_some() {
+            self.closing.close(deadline);
+        }
+        self.unsubscribe_all();
+        if let Some(refresher) = py.allow_threads(|| self.auth_refresher.lock().take()) {
+            refresher.abort();
+        }
+
+        let in_flight = self.in_flight.clone();
+        future_into_py(py, async move {
+            if let Some(OpenClient { rt, client }) = open {
+                let _ = timeout_at(deadline, in_flight.drained()).await;
+                drop(client);
+                rt.shutdown(deadline.saturating_duration_since(Instant::now()));
+            }
+            Ok(())
+        })
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: &PyAny,
+        _exc_value: &PyAny,
+        _traceback: &PyAny,
+    ) -> PyResult<()> {
+        self.close(py, None)
+    }
+
+    fn __aenter__<'p>(slf: PyRef<'p, Self>, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let slf: Py<Self> = slf.into();
+        future_into_py(py, async move { Ok(slf) })
+    }
+
+    fn __aexit__<'p>(
+        &self,
+        py: Python<'p>,
+        _exc_type: &PyAny,
+        _exc_value: &PyAny,
+        _traceback: &PyAny,
+    ) -> PyResult<&'p PyAny> {
+        self.aclose(py, None)
     }
 }
 
@@ -343,21 +898,37 @@ fn init_logging() {
 
 // Exposed for testing
 #[pyfunction]
-fn py_to_rust_to_py(py: Python<'_>, py_val: &PyAny) -> PyResult<PyObject> {
+#[pyo3(signature = (py_val, int_policy=IntPolicy::Strict, int64_as_int=false))]
+fn py_to_rust_to_py(
+    py: Python<'_>,
+    py_val: &PyAny,
+    int_policy: IntPolicy,
+    int64_as_int: bool,
+) -> PyResult<PyObject> {
     // this is just a map
-    match py_to_value(py, py_val) {
-        Ok(val) => Ok(value_to_py(py, val)),
-        Err(err) => Err(err),
-    }
+    let path = Path::Root("value");
+    let val = py_to_value(py, py_val, &path, int_policy)?;
+    value_to_py(py, val, &path, int64_as_int)
+}
+
+// Exposed for testing
+#[pyfunction]
+fn py_to_identity_to_py<'py>(py: Python<'py>, acting_as: &PyDict) -> PyResult<&'py PyDict> {
+    let identity = py_to_identity(py, acting_as)?;
+    identity_to_py(py, &identity)
 }
 
 #[pymodule]
 #[pyo3(name = "_convex")]
 fn _convex(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyConvexClient>()?;
+    m.add_class::<PyAuthState>()?;
     m.add_class::<PyQuerySubscription>()?;
     m.add_class::<PyQuerySetSubscription>()?;
     m.add_function(wrap_pyfunction!(init_logging, m)?)?;
+    m.add_function(wrap_pyfunction!(configure_shared_runtime, m)?)?;
+    m.add_function(wrap_pyfunction!(set_signal_check_interval, m)?)?;
     m.add_function(wrap_pyfunction!(py_to_rust_to_py, m)?)?;
+    m.add_function(wrap_pyfunction!(py_to_identity_to_py, m)?)?;
     Ok(())
 }
@@ -0,0 +1,5 @@
+// pyo3 can't define new custom exceptions when using the common abi
+// `features = ["abi3"]` https://github.com/PyO3/pyo3/issues/1344
+// so these are defined in Python and imported here.
+pyo3::import_exception!(_convex.errors, ConvexTimeoutError);
+pyo3::import_exception!(_convex.errors, ConvexConversionError);
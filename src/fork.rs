@@ -0,0 +1,37 @@
+//! Clients don't survive `os.fork()`. A child process inherits the memory of
+//! its parent but only the thread which forked, so runtimes created before the
+//! fork have no worker threads left to drive them. Worse, locks held by those
+//! threads at the time of the fork are never released, so even shutting such a
+//! runtime down may hang.
+
+use pyo3::{
+    exceptions::PyRuntimeError,
+    PyResult,
+};
+
+/// The process an object was created in.
+#[derive(Clone, Copy, Debug)]
+pub struct OwningProcess(u32);
+
+impl OwningProcess {
+    pub fn current() -> Self {
+        OwningProcess(std::process::id())
+    }
+
+    /// Whether this is the process the object was created in, rather than a
+    /// child forked from it.
+    pub fn is_current(&self) -> bool {
+        self.0 == std::process::id()
+    }
+
+    /// Raises an error when called in a child forked from the owning process.
+    pub fn check(&self, what: &str) -> PyResult<()> {
+        if self.is_current() {
+            return Ok(());
+        }
+        Err(PyRuntimeError::new_err(format!(
+            "{what} was created before the process forked and can't be used in the child \
+             process. Create a new client after forking instead."
+        )))
+    }
+}
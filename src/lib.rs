@@ -12,8 +12,11 @@
 #![warn(missing_docs)]
 #![warn(rustdoc::missing_crate_level_docs)]
 
+mod blocking;
 mod client;
 pub use client::PyConvexClient;
 
+mod errors;
+mod fork;
 mod query_result;
 mod subscription;
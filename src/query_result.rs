@@ -1,8 +1,18 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    fmt,
+};
 
-use convex::ConvexError;
+use convex::{
+    ConvexError,
+    FunctionResult,
+};
 use pyo3::{
-    exceptions::PyException,
+    exceptions::{
+        PyException,
+        PyTypeError,
+        PyValueError,
+    },
     types::{
         PyBool,
         PyBytes,
@@ -10,53 +20,135 @@ use pyo3::{
         PyFloat,
         PyInt,
         PyList,
+        PyMapping,
+        PyMemoryView,
+        PySequence,
         PyString,
+        PyTuple,
     },
+    FromPyObject,
     IntoPy,
     PyAny,
+    PyErr,
     PyObject,
     PyResult,
     Python,
 };
 
+use crate::errors::ConvexConversionError;
+
+/// Where a value sits within the value being converted, like
+/// `args.items[3].price`, for error messages. Each level of the conversion
+/// keeps its segment on the stack, so the path is only rendered on failure.
+#[derive(Clone, Copy)]
+pub enum Path<'a> {
+    Root(&'a str),
+    Field(&'a Path<'a>, &'a str),
+    Index(&'a Path<'a>, usize),
+}
+
+impl fmt::Display for Path<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Path::Root(name) => write!(f, "{name}"),
+            Path::Field(parent, field) => {
+                let is_identifier = field
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if is_identifier {
+                    write!(f, "{parent}.{field}")
+                } else {
+                    write!(f, "{parent}[{field:?}]")
+                }
+            },
+            Path::Index(parent, index) => write!(f, "{parent}[{index}]"),
+        }
+    }
+}
+
+/// An error for the value at `path` which can't be converted.
+pub fn conversion_error(path: &Path<'_>, problem: &str) -> PyErr {
+    let path = path.to_string();
+    ConvexConversionError::new_err((format!("Can't convert {path}: {problem}"), path))
+}
+
+/// A conversion error caused by `cause`, which is chained to it.
+fn conversion_error_from(py: Python<'_>, path: &Path<'_>, problem: &str, cause: PyErr) -> PyErr {
+    let err = conversion_error(path, &format!("{problem}: {}", cause.value(py)));
+    err.set_cause(py, Some(cause));
+    err
+}
+
 // TODO using an enum would be cleaner here
-pub fn value_to_py_wrapped(py: Python<'_>, v: convex::Value) -> PyObject {
+pub fn value_to_py_wrapped(
+    py: Python<'_>,
+    v: convex::Value,
+    int64_as_int: bool,
+) -> PyResult<PyObject> {
     let py_dict = PyDict::new(py);
-    py_dict
-        .set_item("type", PyString::new(py, "value"))
-        .unwrap();
-    py_dict.set_item("value", value_to_py(py, v)).unwrap();
-    py_dict.into()
+    py_dict.set_item("type", PyString::new(py, "value"))?;
+    let value = value_to_py(py, v, &Path::Root("result"), int64_as_int)?;
+    py_dict.set_item("value", value)?;
+    Ok(py_dict.into())
 }
 
-pub fn convex_error_to_py_wrapped(py: Python<'_>, err: ConvexError) -> PyObject {
+pub fn convex_error_to_py_wrapped(
+    py: Python<'_>,
+    err: ConvexError,
+    int64_as_int: bool,
+) -> PyResult<PyObject> {
     let py_dict = PyDict::new(py);
-    py_dict
-        .set_item("type", PyString::new(py, "convexerror"))
-        .unwrap();
-    py_dict.set_item("message", err.message).unwrap();
-    py_dict.set_item("data", value_to_py(py, err.data)).unwrap();
-    py_dict.into()
+    py_dict.set_item("type", PyString::new(py, "convexerror"))?;
+    py_dict.set_item("message", err.message)?;
+    let data = value_to_py(py, err.data, &Path::Root("data"), int64_as_int)?;
+    py_dict.set_item("data", data)?;
+    Ok(py_dict.into())
 }
 
-pub fn value_to_py(py: Python<'_>, v: convex::Value) -> PyObject {
-    match v {
-        convex::Value::Null => py.None(),
-        convex::Value::Int64(val) => {
-            let int64_module = py
-                .import("_convex.int64")
-                .expect("Couldn't import _convex.int64");
-            let int_64_class = int64_module
-                .getattr("ConvexInt64")
-                .expect("Couldn't import ConvexInt64 from _convex.int64");
-            let py_int = val.into_py(py);
-            let obj: PyObject = int_64_class
-                .call((py_int.clone(),), None)
-                .unwrap_or_else(|_| panic!("Couldn't construct ConvexInt64() from {:?}", py_int))
-                .into();
-            println!("!!!! {:?} {}", py_int, py_int);
-            obj
+/// Convert the result of a Convex function into the wrapped form the Python
+/// layer expects, raising for error messages.
+pub fn function_result_to_py_result(
+    py: Python<'_>,
+    result: FunctionResult,
+    int64_as_int: bool,
+) -> PyResult<PyObject> {
+    match result {
+        FunctionResult::Value(v) => value_to_py_wrapped(py, v, int64_as_int),
+        FunctionResult::ErrorMessage(e) => Err(PyException::new_err(e)),
+        FunctionResult::ConvexError(v) => {
+            // pyo3 can't defined new custom exceptions when using the common abi
+            // `features = ["abi3"]` https://github.com/PyO3/pyo3/issues/1344
+            // so we define this error in Python. So just return a wrapped one.
+            convex_error_to_py_wrapped(py, v, int64_as_int)
         },
+    }
+}
+
+/// Translate a Convex value to Python. `Int64`s become `ConvexInt64`s, so that
+/// they can be sent back as `Int64`s, or plain ints with `int64_as_int`.
+///
+/// Raises a `ConvexConversionError` with the location of the value which can't
+/// be converted, `v` being at `path`.
+pub fn value_to_py(
+    py: Python<'_>,
+    v: convex::Value,
+    path: &Path<'_>,
+    int64_as_int: bool,
+) -> PyResult<PyObject> {
+    Ok(match v {
+        convex::Value::Null => py.None(),
+        convex::Value::Int64(val) if int64_as_int => val.into_py(py),
+        convex::Value::Int64(val) => py
+            .import("_convex.int64")
+            .and_then(|int64_module| int64_module.getattr("ConvexInt64"))
+            .and_then(|int_64_class| int_64_class.call1((val,)))
+            .map_err(|e| {
+                let problem = format!("Couldn't construct ConvexInt64({val})");
+                conversion_error_from(py, path, &problem, e)
+            })?
+            .into(),
 
         convex::Value::Float64(val) => PyFloat::new(py, val).into(),
         convex::Value::Boolean(val) => PyBool::new(py, val).into(),
@@ -64,26 +156,181 @@ pub fn value_to_py(py: Python<'_>, v: convex::Value) -> PyObject {
         convex::Value::Bytes(val) => PyBytes::new(py, &val).into(),
         convex::Value::Array(arr) => {
             let py_list = PyList::empty(py);
-            for item in arr {
-                py_list.append(value_to_py(py, item)).unwrap();
+            for (index, item) in arr.into_iter().enumerate() {
+                let item = value_to_py(py, item, &Path::Index(path, index), int64_as_int)?;
+                py_list.append(item)?;
             }
             py_list.into()
         },
         convex::Value::Object(obj) => {
             let py_dict = PyDict::new(py);
             for (key, value) in obj {
-                py_dict.set_item(key, value_to_py(py, value)).unwrap();
+                let value = value_to_py(py, value, &Path::Field(path, &key), int64_as_int)?;
+                py_dict.set_item(key, value)?;
             }
             py_dict.into()
         },
+    })
+}
+
+/// The largest integers which can be converted to a float without losing
+/// precision.
+const MIN_SAFE_INTEGER: i64 = -(1 << 53);
+const MAX_SAFE_INTEGER: i64 = 1 << 53;
+const MAX_IDENTIFIER_LEN: usize = 1024;
+
+/// How Python ints, which Convex functions receive as floats, are converted
+/// when they don't fit in a float without losing precision.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum IntPolicy {
+    /// Raise rather than lose precision.
+    #[default]
+    Strict,
+    /// Convert every int to a float, rounding those which don't fit.
+    Float,
+    /// Convert ints which don't fit to `Int64`, which Convex functions receive
+    /// as `BigInt`s.
+    Int64,
+}
+
+impl<'source> FromPyObject<'source> for IntPolicy {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        match ob.extract::<&str>()? {
+            "strict" => Ok(IntPolicy::Strict),
+            "float" => Ok(IntPolicy::Float),
+            "int64" => Ok(IntPolicy::Int64),
+            policy => Err(PyValueError::new_err(format!(
+                "Invalid int_policy {policy:?}: expected \"strict\", \"float\" or \"int64\""
+            ))),
+        }
+    }
+}
+
+/// Translate a Python int according to `int_policy`.
+fn int_to_value(
+    py: Python<'_>,
+    py_val: &PyAny,
+    path: &Path<'_>,
+    int_policy: IntPolicy,
+) -> PyResult<convex::Value> {
+    let val = py_val.extract::<i64>().ok();
+    match (int_policy, val) {
+        (_, Some(val)) if (MIN_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(&val) => {
+            Ok(convex::Value::Float64(val as f64))
+        },
+        (IntPolicy::Float, _) => {
+            let val: f64 = py_val.extract().map_err(|e| {
+                conversion_error_from(py, path, "Integer doesn't fit in a float", e)
+            })?;
+            Ok(convex::Value::Float64(val))
+        },
+        (IntPolicy::Int64, Some(val)) => Ok(convex::Value::Int64(val)),
+        (IntPolicy::Int64, None) => Err(conversion_error(
+            path,
+            &format!(
+                "Integer {} is outside the range of a Convex `Int64` (-2^63 to 2^63 - 1)",
+                py_val.str()?
+            ),
+        )),
+        (IntPolicy::Strict, _) => Err(conversion_error(
+            path,
+            &format!(
+                "Integer {} is outside the range of a Convex `Float64` (-2^53 to 2^53). \
+                 Consider using a `ConvexInt64`, which corresponds to a `BigInt` in \
+                 JavaScript Convex functions, or the \"int64\" int_policy",
+                py_val.str()?
+            ),
+        )),
     }
 }
 
-// TODO Implement all or most of the coercions from the Python client.
-/// Translate a Python value to Rust, doing isinstance coersion (e.g. subclasses
-/// of list will be interpreted as lists) but not other conversions (e.g. tuple
-/// to list).
-pub fn py_to_value(py: Python<'_>, py_val: &PyAny) -> PyResult<convex::Value> {
+/// Checks that `field` can name a field of a Convex object.
+fn validate_field_name(field: &str, path: &Path<'_>) -> PyResult<()> {
+    let problem = if field.chars().count() > MAX_IDENTIFIER_LEN {
+        format!("Field name {field} exceeds maximum field name length {MAX_IDENTIFIER_LEN}")
+    } else if field.starts_with('$') {
+        format!("Field name {field} starts with a '$', which is reserved")
+    } else if let Some(c) = field.chars().find(|c| !(' '..='~').contains(c)) {
+        format!(
+            "Field name '{field}' has invalid character '{c}': Field names can only contain \
+             non-control ASCII characters"
+        )
+    } else {
+        return Ok(());
+    };
+    Err(conversion_error(path, &problem))
+}
+
+/// Translate a mapping to the fields of a Convex object. `keys` describes the
+/// keys in errors.
+pub fn mapping_to_object(
+    py: Python<'_>,
+    mapping: &PyAny,
+    path: &Path<'_>,
+    keys: &str,
+    int_policy: IntPolicy,
+) -> PyResult<BTreeMap<String, convex::Value>> {
+    let items: Vec<(&PyAny, &PyAny)> = match mapping.downcast::<PyDict>() {
+        Ok(py_dict) => py_dict.iter().collect(),
+        Err(_) => mapping
+            .call_method0("items")?
+            .iter()?
+            .map(|item| item?.extract())
+            .collect::<PyResult<_>>()?,
+    };
+    let mut map: BTreeMap<String, convex::Value> = BTreeMap::new();
+    for (key, value) in items {
+        let Ok(key) = key.downcast::<PyString>() else {
+            return Err(conversion_error(
+                path,
+                &format!(
+                    "{keys} must be strings, found {} of type {}",
+                    key.repr()?,
+                    key.get_type().name()?
+                ),
+            ));
+        };
+        let key = key.to_str()?;
+        let field_path = Path::Field(path, key);
+        validate_field_name(key, &field_path)?;
+        let inner_value: convex::Value = py_to_value(py, value, &field_path, int_policy)?;
+        map.insert(key.to_string(), inner_value);
+    }
+    Ok(map)
+}
+
+fn iterable_to_array(
+    py: Python<'_>,
+    iterable: &PyAny,
+    path: &Path<'_>,
+    int_policy: IntPolicy,
+) -> PyResult<convex::Value> {
+    let mut vec: Vec<convex::Value> = Vec::new();
+    for (index, item) in iterable.iter()?.enumerate() {
+        let path = Path::Index(path, index);
+        let inner_value: convex::Value = py_to_value(py, item?, &path, int_policy)?;
+        vec.push(inner_value);
+    }
+    Ok(convex::Value::Array(vec))
+}
+
+/// Translate a Python value to Rust, coercing values the same way as
+/// `coerce_to_convex()` in `values.py`:
+/// - ints become floats, if they fit without losing precision, otherwise
+///   `int_policy` applies;
+/// - tuples and other sequences become arrays;
+/// - mappings become objects, whose field names are validated;
+/// - objects supporting the buffer protocol, like `bytearray`, become bytes;
+/// - subclasses of supported types are converted like their base type.
+///
+/// Raises a `ConvexConversionError` with the location of the value which can't
+/// be converted, `py_val` being at `path`.
+pub fn py_to_value(
+    py: Python<'_>,
+    py_val: &PyAny,
+    path: &Path<'_>,
+    int_policy: IntPolicy,
+) -> PyResult<convex::Value> {
     let int64_module = py.import("_convex.int64")?;
     let int_64_class = int64_module.getattr("ConvexInt64")?;
 
@@ -94,16 +341,17 @@ pub fn py_to_value(py: Python<'_>, py_val: &PyAny) -> PyResult<convex::Value> {
     }
     if py_val.is_instance_of::<PyInt>() {
         // Note conversion from int to float
-        let val: f64 = py_val.extract()?;
-        return Ok(convex::Value::Float64(val));
+        return int_to_value(py, py_val, path, int_policy);
     }
     if py_val.is_instance_of::<PyFloat>() {
         let val: f64 = py_val.extract::<f64>()?;
         return Ok(convex::Value::Float64(val));
     }
     if py_val.is_instance(int_64_class)? {
-        let value = py_val.getattr("value")?;
-        let val: i64 = value.extract()?;
+        let val: i64 = py_val
+            .getattr("value")
+            .and_then(|value| value.extract())
+            .map_err(|e| conversion_error_from(py, path, "Invalid ConvexInt64", e))?;
         return Ok(convex::Value::Int64(val));
     }
     if py_val.is_instance_of::<PyString>() {
@@ -114,40 +362,39 @@ pub fn py_to_value(py: Python<'_>, py_val: &PyAny) -> PyResult<convex::Value> {
         let val: Vec<u8> = py_val.extract::<Vec<u8>>()?;
         return Ok(convex::Value::Bytes(val));
     }
-    if py_val.is_instance_of::<PyList>() {
-        let py_list = py_val.downcast::<PyList>()?;
-        let mut vec: Vec<convex::Value> = Vec::new();
-        for item in py_list {
-            let inner_value: convex::Value = py_to_value(py, item)?;
-            vec.push(inner_value);
-        }
-        return Ok(convex::Value::Array(vec));
+    if py_val.is_instance_of::<PyList>() || py_val.is_instance_of::<PyTuple>() {
+        return iterable_to_array(py, py_val, path, int_policy);
     }
     if py_val.is_instance_of::<PyDict>() {
-        let py_dict = py_val.downcast::<PyDict>()?;
-        let mut map: BTreeMap<String, convex::Value> = BTreeMap::new();
-        for (key, value) in py_dict.iter() {
-            let inner_value: convex::Value = py_to_value(py, value)?;
-            let inner_key: convex::Value = py_to_value(py, key)?;
-            match inner_key {
-                convex::Value::String(s) => map.insert(s, inner_value),
-                _ => {
-                    return Err(PyException::new_err(format!(
-                        "Bad key for Convex object: {:?}",
-                        key
-                    )))
-                },
-            };
-        }
+        let map = mapping_to_object(py, py_val, path, "Convex object keys", int_policy)?;
         return Ok(convex::Value::Object(map));
     }
     if py_val.is_none() {
         return Ok(convex::Value::Null);
     }
 
-    Err(PyException::new_err(format!(
-        "Failed to serialize to Convex value {:?} of type {:?}",
-        py_val,
-        py_val.get_type()
-    )))
+    match PyMemoryView::from(py_val) {
+        Ok(view) => {
+            let val = view.call_method0("tobytes")?.downcast::<PyBytes>()?;
+            return Ok(convex::Value::Bytes(val.as_bytes().to_vec()));
+        },
+        Err(e) if e.is_instance_of::<PyTypeError>(py) => {},
+        Err(e) => return Err(e),
+    }
+    if py_val.downcast::<PyMapping>().is_ok() {
+        let map = mapping_to_object(py, py_val, path, "Convex object keys", int_policy)?;
+        return Ok(convex::Value::Object(map));
+    }
+    if py_val.downcast::<PySequence>().is_ok() {
+        return iterable_to_array(py, py_val, path, int_policy);
+    }
+
+    Err(conversion_error(
+        path,
+        &format!(
+            "{} of type {} isn't a Convex value",
+            py_val.repr()?,
+            py_val.get_type().name()?
+        ),
+    ))
 }
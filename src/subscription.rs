@@ -4,7 +4,15 @@ use std::{
         Hash,
         Hasher,
     },
-    sync::Arc,
+    mem,
+    ops::{
+        Deref,
+        DerefMut,
+    },
+    sync::{
+        Arc,
+        Weak,
+    },
 };
 
 use convex::{
@@ -17,36 +25,172 @@ use pyo3::{
     exceptions::{
         PyException,
         PyNotImplementedError,
+        PyRuntimeError,
         PyStopAsyncIteration,
         PyStopIteration,
     },
     prelude::*,
     pyclass::CompareOp,
-    types::PyDict,
-};
-use tokio::time::{
-    sleep,
-    Duration,
+    types::{
+        PyDict,
+        PyString,
+    },
 };
 
-use crate::query_result::{
-    convex_error_to_py_wrapped,
-    value_to_py,
-    value_to_py_wrapped,
+use crate::{
+    blocking,
+    client::future_into_py,
+    fork::OwningProcess,
+    query_result::{
+        convex_error_to_py_wrapped,
+        value_to_py,
+        value_to_py_wrapped,
+        Path,
+    },
 };
 
+/// A subscription shared by the Python object wrapping it and the call waiting
+/// for its next result, which leases it out of the slot while waiting.
+pub enum SubscriptionSlot<S> {
+    Idle(S),
+    /// Leased out to a call waiting for the next result.
+    Waiting,
+    Unsubscribed,
+}
+
+type SharedSlot<S> = Arc<Mutex<SubscriptionSlot<S>>>;
+
+impl<S> SubscriptionSlot<S> {
+    fn shared(subscription: S) -> SharedSlot<S> {
+        Arc::new(Mutex::new(SubscriptionSlot::Idle(subscription)))
+    }
+
+    /// Whether the subscription hasn't been unsubscribed, including while a
+    /// call is waiting for its next result.
+    fn exists(&self) -> bool {
+        !matches!(self, SubscriptionSlot::Unsubscribed)
+    }
+
+    /// Leases the subscription out of `slot`, or returns `None` if it was
+    /// unsubscribed. Only one call can wait for the next result at a time.
+    fn lease(slot: &SharedSlot<S>) -> PyResult<Option<Lease<S>>> {
+        let mut state = slot.lock();
+        match mem::replace(&mut *state, SubscriptionSlot::Waiting) {
+            SubscriptionSlot::Idle(subscription) => Ok(Some(Lease {
+                slot: slot.clone(),
+                subscription: Some(subscription),
+            })),
+            SubscriptionSlot::Waiting => Err(PyRuntimeError::new_err(
+                "Another call is already waiting for the next result of this subscription",
+            )),
+            SubscriptionSlot::Unsubscribed => {
+                *state = SubscriptionSlot::Unsubscribed;
+                Ok(None)
+            },
+        }
+    }
+
+    /// Drops the subscription, which unsubscribes from the query, or has it
+    /// dropped once returned by the call waiting for it.
+    fn unsubscribe(&mut self) {
+        *self = SubscriptionSlot::Unsubscribed;
+    }
+}
+
+/// A subscription leased out of its slot. It's put back when the lease is
+/// dropped, including when the call waiting for it is interrupted, unless it
+/// was unsubscribed meanwhile.
+struct Lease<S> {
+    slot: SharedSlot<S>,
+    subscription: Option<S>,
+}
+
+impl<S> Deref for Lease<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        self.subscription
+            .as_ref()
+            .expect("The subscription is only taken when dropped")
+    }
+}
+
+impl<S> DerefMut for Lease<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        self.subscription
+            .as_mut()
+            .expect("The subscription is only taken when dropped")
+    }
+}
+
+impl<S> Drop for Lease<S> {
+    fn drop(&mut self) {
+        let mut state = self.slot.lock();
+        if matches!(*state, SubscriptionSlot::Waiting) {
+            if let Some(subscription) = self.subscription.take() {
+                *state = SubscriptionSlot::Idle(subscription);
+            }
+        }
+    }
+}
+
 #[pyclass]
 pub struct PyQuerySubscription {
-    // TODO document here why this needs to be an Arc<Mutex<Option<Sub>>>
-    inner: Arc<Mutex<Option<convex::QuerySubscription>>>,
+    inner: SharedSlot<convex::QuerySubscription>,
+    id: SubscriberId,
     pub rt_handle: Option<tokio::runtime::Handle>,
+    pub offload_nested_calls: bool,
+    /// Whether `Int64` results are decoded as plain ints.
+    pub int64_as_int: bool,
+    owner: OwningProcess,
 }
 
 impl From<convex::QuerySubscription> for PyQuerySubscription {
     fn from(query_sub: convex::QuerySubscription) -> Self {
         PyQuerySubscription {
-            inner: Arc::new(Mutex::new(Some(query_sub))),
+            id: *query_sub.id(),
+            inner: SubscriptionSlot::shared(query_sub),
             rt_handle: None,
+            offload_nested_calls: false,
+            int64_as_int: false,
+            owner: OwningProcess::current(),
+        }
+    }
+}
+
+impl PyQuerySubscription {
+    pub fn downgrade(&self) -> WeakSubscription {
+        WeakSubscription::Query(Arc::downgrade(&self.inner))
+    }
+}
+
+/// A reference to a subscription which doesn't keep it alive, so that the
+/// client which created it can unsubscribe it when closed.
+pub enum WeakSubscription {
+    Query(Weak<Mutex<SubscriptionSlot<convex::QuerySubscription>>>),
+    QuerySet(Weak<Mutex<SubscriptionSlot<convex::QuerySetSubscription>>>),
+}
+
+impl WeakSubscription {
+    pub fn is_alive(&self) -> bool {
+        match self {
+            WeakSubscription::Query(inner) => inner.strong_count() > 0,
+            WeakSubscription::QuerySet(inner) => inner.strong_count() > 0,
+        }
+    }
+
+    pub fn unsubscribe(&self) {
+        match self {
+            WeakSubscription::Query(inner) => {
+                if let Some(inner) = inner.upgrade() {
+                    inner.lock().unsubscribe();
+                }
+            },
+            WeakSubscription::QuerySet(inner) => {
+                if let Some(inner) = inner.upgrade() {
+                    inner.lock().unsubscribe();
+                }
+            },
         }
     }
 }
@@ -91,82 +235,86 @@ impl PySubscriberId {
     }
 }
 
-async fn check_python_signals_periodically() -> PyResult<()> {
-    loop {
-        sleep(Duration::from_secs(1)).await;
-        Python::with_gil(|py| py.check_signals())?;
-    }
-}
-
 #[pymethods]
 impl PyQuerySubscription {
     fn exists(&self, py: Python) -> Py<PyAny> {
-        let exists = self.inner.lock().is_some();
+        let exists = self.inner.lock().exists();
         exists.into_py(py)
     }
 
     #[getter]
     fn id(&self, py: Python) -> PyObject {
-        let query_sub = self.inner.clone();
-        let query_sub_inner = query_sub.lock().take().unwrap();
-        let sub_id: SubscriberId = *query_sub_inner.id();
-        let _ = query_sub.lock().insert(query_sub_inner);
-        let py_sub_id: PySubscriberId = sub_id.into();
+        let py_sub_id: PySubscriberId = self.id.into();
         py_sub_id.into_py(py)
     }
 
+    /// Whether `Int64` results are decoded as plain ints.
+    #[getter]
+    fn int64_as_int(&self) -> bool {
+        self.int64_as_int
+    }
+
     // Drops the inner subscription object, which causes a
     // downstream unsubscription event.
     fn unsubscribe(&self) {
-        self.inner.lock().take();
+        self.inner.lock().unsubscribe();
     }
 
-    fn next(&mut self, py: Python) -> PyResult<PyObject> {
+    fn next(&self, py: Python) -> PyResult<PyObject> {
+        self.owner.check("This subscription")?;
         let query_sub = self.inner.clone();
-        let res = self.rt_handle.as_mut().unwrap().block_on(async {
-            tokio::select!(
-                res1 = async move {
-                    let query_sub_inner = query_sub.lock().take();
-                    if query_sub_inner.is_none() {
-                        return Err(PyStopIteration::new_err("Stream requires reset"));
-                    }
-                    let mut query_sub_inner = query_sub_inner.unwrap();
-                    let res = query_sub_inner.next().await;
-                    let _ = query_sub.lock().insert(query_sub_inner);
-                    Ok(res)} => res1,
-                res2 = check_python_signals_periodically() => Err(res2.err().unwrap())
-            )
+        // Don't block on the runtime of a closed client, which may already be
+        // shut down.
+        if !query_sub.lock().exists() {
+            return Err(PyStopIteration::new_err("Stream requires reset"));
+        }
+        let rt_handle = self.rt_handle.as_ref().unwrap();
+        // Release the GIL while waiting for the next result so other Python
+        // threads can make progress.
+        let res = py.allow_threads(|| {
+            blocking::block_on(rt_handle, self.offload_nested_calls, async move {
+                let Some(mut query_sub_inner) = SubscriptionSlot::lease(&query_sub)? else {
+                    return Err(PyStopIteration::new_err("Stream requires reset"));
+                };
+                Ok(query_sub_inner.next().await)
+            })
         })?;
-        match res.unwrap() {
-            FunctionResult::Value(v) => Ok(value_to_py_wrapped(py, v)),
+        let Some(res) = res else {
+            return Err(PyStopIteration::new_err("Client closed"));
+        };
+        match res {
+            FunctionResult::Value(v) => value_to_py_wrapped(py, v, self.int64_as_int),
             FunctionResult::ErrorMessage(e) => Err(PyException::new_err(e)),
             FunctionResult::ConvexError(v) => {
                 // pyo3 can't defined new custom exceptions when using the common abi
                 // `features = ["abi3"]` https://github.com/PyO3/pyo3/issues/1344
                 // so we define this error in Python. So just return a wrapped one.
-                Ok(convex_error_to_py_wrapped(py, v))
+                convex_error_to_py_wrapped(py, v, self.int64_as_int)
             },
         }
     }
 
     fn anext(slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+        slf.owner.check("This subscription")?;
         let query_sub = slf.inner.clone();
-        let fut = pyo3_asyncio::tokio::future_into_py(slf.py(), async move {
-            let query_sub_inner = query_sub.lock().take();
-            if query_sub_inner.is_none() {
+        let int64_as_int = slf.int64_as_int;
+        let fut = future_into_py(slf.py(), async move {
+            let Some(mut query_sub_inner) = SubscriptionSlot::lease(&query_sub)? else {
                 return Err(PyStopAsyncIteration::new_err("Stream requires reset"));
-            }
-            let mut query_sub_inner = query_sub_inner.unwrap();
+            };
             let res = query_sub_inner.next().await;
-            let _ = query_sub.lock().insert(query_sub_inner);
-            Python::with_gil(|py| match res.unwrap() {
-                FunctionResult::Value(v) => Ok(value_to_py_wrapped(py, v)),
+            drop(query_sub_inner);
+            let Some(res) = res else {
+                return Err(PyStopAsyncIteration::new_err("Client closed"));
+            };
+            Python::with_gil(|py| match res {
+                FunctionResult::Value(v) => value_to_py_wrapped(py, v, int64_as_int),
                 FunctionResult::ErrorMessage(e) => Err(PyException::new_err(e)),
                 FunctionResult::ConvexError(v) => {
                     // pyo3 can't defined new custom exceptions when using the common abi
                     // `features = ["abi3"]` https://github.com/PyO3/pyo3/issues/1344
                     // so we define this error in Python. So just return a wrapped one.
-                    Ok(convex_error_to_py_wrapped(py, v))
+                    convex_error_to_py_wrapped(py, v, int64_as_int)
                 },
             })
         })?;
@@ -176,43 +324,67 @@ impl PyQuerySubscription {
 
 #[pyclass]
 pub struct PyQuerySetSubscription {
-    inner: Arc<Mutex<Option<convex::QuerySetSubscription>>>,
+    inner: SharedSlot<convex::QuerySetSubscription>,
     pub rt_handle: Option<tokio::runtime::Handle>,
+    pub offload_nested_calls: bool,
+    /// Whether `Int64` results are decoded as plain ints.
+    pub int64_as_int: bool,
+    owner: OwningProcess,
 }
 
 impl From<convex::QuerySetSubscription> for PyQuerySetSubscription {
     fn from(query_set_sub: convex::QuerySetSubscription) -> Self {
         PyQuerySetSubscription {
-            inner: Arc::new(Mutex::new(Some(query_set_sub))),
+            inner: SubscriptionSlot::shared(query_set_sub),
             rt_handle: None,
+            offload_nested_calls: false,
+            int64_as_int: false,
+            owner: OwningProcess::current(),
         }
     }
 }
 
+impl PyQuerySetSubscription {
+    pub fn downgrade(&self) -> WeakSubscription {
+        WeakSubscription::QuerySet(Arc::downgrade(&self.inner))
+    }
+}
+
 #[pymethods]
 impl PyQuerySetSubscription {
     fn exists(&self, py: Python) -> Py<PyAny> {
-        let exists = self.inner.lock().is_some();
+        let exists = self.inner.lock().exists();
         exists.into_py(py)
     }
 
-    fn next(&mut self, py: Python) -> PyResult<PyObject> {
+    /// Whether `Int64` results are decoded as plain ints.
+    #[getter]
+    fn int64_as_int(&self) -> bool {
+        self.int64_as_int
+    }
+
+    fn next(&self, py: Python) -> PyResult<PyObject> {
+        self.owner.check("This subscription")?;
         let query_sub = self.inner.clone();
-        let res = self.rt_handle.as_mut().unwrap().block_on(async {
-            tokio::select!(
-                res1 = async move {
-                    let query_sub_inner = query_sub.lock().take();
-                    if query_sub_inner.is_none() {
-                        return Err(PyStopIteration::new_err("Stream requires reset"));
-                    }
-                    let mut query_sub_inner = query_sub_inner.unwrap();
-                    let res = query_sub_inner.next().await;
-                    let _ = query_sub.lock().insert(query_sub_inner);
-                    Ok(res)} => res1,
-                res2 = check_python_signals_periodically() => Err(res2.err().unwrap())
-            )
+        // Don't block on the runtime of a closed client, which may already be
+        // shut down.
+        if !query_sub.lock().exists() {
+            return Err(PyStopIteration::new_err("Stream requires reset"));
+        }
+        let rt_handle = self.rt_handle.as_ref().unwrap();
+        // Release the GIL while waiting for the next result so other Python
+        // threads can make progress.
+        let res = py.allow_threads(|| {
+            blocking::block_on(rt_handle, self.offload_nested_calls, async move {
+                let Some(mut query_sub_inner) = SubscriptionSlot::lease(&query_sub)? else {
+                    return Err(PyStopIteration::new_err("Stream requires reset"));
+                };
+                Ok(query_sub_inner.next().await)
+            })
         })?;
-        let query_results = res.unwrap();
+        let Some(query_results) = res else {
+            return Err(PyStopIteration::new_err("Client closed"));
+        };
         let py_dict = PyDict::new(py);
         for (sub_id, function_result) in query_results.iter() {
             if function_result.is_none() {
@@ -221,16 +393,16 @@ impl PyQuerySetSubscription {
             let py_sub_id: PySubscriberId = (*sub_id).into();
 
             let sub_value: PyObject = match function_result.unwrap() {
-                FunctionResult::Value(v) => value_to_py_wrapped(py, v.clone()),
+                FunctionResult::Value(v) => value_to_py_wrapped(py, v.clone(), self.int64_as_int)?,
                 FunctionResult::ErrorMessage(e) => {
                     // TODO this is wrong!
-                    value_to_py(py, convex::Value::String(e.clone()))
+                    PyString::new(py, e).into()
                 },
                 FunctionResult::ConvexError(v) => {
                     // pyo3 can't defined new custom exceptions when using the common abi
                     // `features = ["abi3"]` https://github.com/PyO3/pyo3/issues/1344
                     // so we define this error in Python. So just return a wrapped one.
-                    convex_error_to_py_wrapped(py, v.clone()).to_object(py)
+                    convex_error_to_py_wrapped(py, v.clone(), self.int64_as_int)?
                 },
             };
             py_dict.set_item(py_sub_id.into_py(py), sub_value).unwrap();
@@ -239,36 +411,36 @@ impl PyQuerySetSubscription {
     }
 
     fn anext(slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+        slf.owner.check("This subscription")?;
         let query_sub = slf.inner.clone();
-        let fut: &PyAny = pyo3_asyncio::tokio::future_into_py(slf.py(), async move {
-            let query_sub_inner = query_sub.lock().take();
-            if query_sub_inner.is_none() {
+        let int64_as_int = slf.int64_as_int;
+        let fut: &PyAny = future_into_py(slf.py(), async move {
+            let Some(mut query_sub_inner) = SubscriptionSlot::lease(&query_sub)? else {
                 return Err(PyStopAsyncIteration::new_err("Stream requires reset"));
-            }
-            let mut query_sub_inner = query_sub_inner.unwrap();
+            };
             let res = query_sub_inner.next().await;
-            let _ = query_sub.lock().insert(query_sub_inner);
+            drop(query_sub_inner);
+            let Some(query_results) = res else {
+                return Err(PyStopAsyncIteration::new_err("Client closed"));
+            };
 
             Python::with_gil(|py| -> PyResult<PyObject> {
-                let query_results = res.unwrap();
                 let py_dict = PyDict::new(py);
                 for (sub_id, function_result) in query_results.iter() {
                     if function_result.is_none() {
                         continue;
                     }
                     let py_sub_id: PySubscriberId = (*sub_id).into();
+                    let result = Path::Root("result");
                     let sub_value: PyObject = match function_result.unwrap() {
-                        FunctionResult::Value(v) => value_to_py(py, v.clone()),
-                        FunctionResult::ErrorMessage(e) => {
-                            value_to_py(py, convex::Value::String(e.to_string()))
+                        FunctionResult::Value(v) => {
+                            value_to_py(py, v.clone(), &result, int64_as_int)?
                         },
+                        FunctionResult::ErrorMessage(e) => PyString::new(py, e).into(),
                         FunctionResult::ConvexError(e) => {
                             let e = e.clone();
-                            (
-                                value_to_py(py, convex::Value::String(e.message)),
-                                value_to_py(py, e.data),
-                            )
-                                .to_object(py)
+                            let data = value_to_py(py, e.data, &Path::Root("data"), int64_as_int)?;
+                            (e.message, data).to_object(py)
                         },
                     };
                     py_dict.set_item(py_sub_id.into_py(py), sub_value).unwrap();
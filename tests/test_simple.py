@@ -1,4 +1,27 @@
-from _convex import PyConvexClient
+import asyncio
+import base64
+import datetime
+import json
+import os
+import signal
+import subprocess
+import sys
+import threading
+import time
+from concurrent.futures import ThreadPoolExecutor
+from pathlib import Path
+from typing import List
+
+import pytest
+from _convex import (
+    ConvexConversionError,
+    ConvexTimeoutError,
+    PyAuthState,
+    PyConvexClient,
+    configure_shared_runtime,
+    py_to_identity_to_py,
+    set_signal_check_interval,
+)
 from convex import ConvexClient
 
 
@@ -9,3 +32,473 @@ def test_rust_instantiation() -> None:
 def test_instantiation() -> None:
     # this is currently completely different code (HTTP client)
     ConvexClient("https://made-up-animal.convex.cloud")
+
+
+def test_timeout() -> None:
+    # this deployment doesn't exist, so the query never completes
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    with pytest.raises(ConvexTimeoutError):
+        client.query("users:list", timeout=0.1)
+
+
+def test_awaitable_timeout() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud", timeout=0.1)
+
+    async def main() -> None:
+        with pytest.raises(ConvexTimeoutError):
+            await client.aquery("users:list")
+        with pytest.raises(ConvexTimeoutError):
+            await client.amutation("users:add", timeout=0.1)
+
+    asyncio.run(main())
+
+
+def test_concurrent_calls() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+
+    def query() -> None:
+        with pytest.raises(ConvexTimeoutError):
+            client.query("users:list", timeout=0.5)
+
+    # calls from several threads wait concurrently instead of failing to
+    # borrow the client
+    with ThreadPoolExecutor(max_workers=4) as executor:
+        for future in [executor.submit(query) for _ in range(4)]:
+            future.result()
+
+
+def test_awaitables() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    when = datetime.datetime.now()
+
+    async def main() -> None:
+        with pytest.raises(ConvexConversionError, match="args.when"):
+            await client.aquery("events:list", {"when": when})
+        with pytest.raises(ConvexConversionError, match="args.when"):
+            await client.amutation("events:add", {"when": when})
+        with pytest.raises(ConvexConversionError, match="args.when"):
+            await client.aaction("events:notify", {"when": when})
+
+        # this deployment doesn't exist, so calls never complete until they
+        # are cancelled
+        for call in [client.aquery, client.amutation, client.aaction]:
+            with pytest.raises(asyncio.TimeoutError):
+                await asyncio.wait_for(call("users:list"), timeout=0.1)
+
+    asyncio.run(main())
+    # the cancelled mutation is still in flight, so closing waits for it
+    start = time.monotonic()
+    client.close(timeout=0.2)
+    assert 0.2 <= time.monotonic() - start < 1
+
+
+def test_shared_runtime() -> None:
+    clients = [
+        PyConvexClient("https://made-up-animal.convex.cloud", shared_runtime=True)
+        for _ in range(2)
+    ]
+    for client in clients:
+        with pytest.raises(ConvexTimeoutError):
+            client.query("users:list", timeout=0.1)
+        client.close()
+    # the runtime is already running
+    with pytest.raises(RuntimeError):
+        configure_shared_runtime(2)
+
+
+def test_configure_shared_runtime_after_awaitable() -> None:
+    # the runtime is process-wide, so start it from a fresh process
+    script = """
+import asyncio
+from _convex import ConvexTimeoutError, PyConvexClient, configure_shared_runtime
+
+async def main():
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    try:
+        await client.aquery("users:list", timeout=0.1)
+    except ConvexTimeoutError:
+        pass
+
+asyncio.run(main())
+try:
+    configure_shared_runtime(2)
+except RuntimeError:
+    raise SystemExit(0)
+raise SystemExit(1)
+"""
+    assert subprocess.run([sys.executable, "-c", script]).returncode == 0
+
+
+def test_close() -> None:
+    with PyConvexClient("https://made-up-animal.convex.cloud") as client:
+        pass
+    with pytest.raises(RuntimeError):
+        client.query("users:list")
+    # closing again does nothing
+    client.close()
+
+
+def test_close_while_waiting_for_subscription() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    subscription = client.subscribe("users:list", timeout=5)
+
+    async def main() -> None:
+        # this deployment doesn't exist, so there is no result until closing
+        waiting = asyncio.ensure_future(subscription.anext())
+        await asyncio.sleep(0.1)
+        await client.aclose()
+        with pytest.raises(StopAsyncIteration, match="Client closed"):
+            await waiting
+
+    asyncio.run(main())
+    assert not subscription.exists()
+
+
+def test_close_stops_calls() -> None:
+    client = ConvexClient("https://made-up-animal.convex.cloud")
+    subscription = client.subscribe("users:list")
+    raised: List[BaseException] = []
+
+    def query() -> None:
+        try:
+            client.query("users:list")
+        except BaseException as e:
+            raised.append(e)
+
+    # this deployment doesn't exist, so the query waits until closing
+    thread = threading.Thread(target=query)
+    thread.start()
+    time.sleep(0.1)
+    start = time.monotonic()
+    client.close()
+    thread.join(timeout=10)
+    assert time.monotonic() - start < 1
+    assert isinstance(raised[0], RuntimeError)
+    # iterating stops instead of subscribing again with the closed client
+    with pytest.raises(StopIteration):
+        next(subscription)
+
+
+def test_subscription_shared_by_threads() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    subscription = client.subscribe("users:list", timeout=5)
+    raised: List[BaseException] = []
+
+    def wait() -> None:
+        try:
+            subscription.next()
+        except BaseException as e:
+            raised.append(e)
+
+    # this deployment doesn't exist, so there is no result until closing
+    thread = threading.Thread(target=wait)
+    thread.start()
+    time.sleep(0.1)
+    # the subscription is still there while another thread waits for it
+    assert subscription.exists()
+    assert subscription.id == subscription.id
+    with pytest.raises(RuntimeError, match="already waiting"):
+        subscription.next()
+    client.close()
+    thread.join(timeout=10)
+    assert isinstance(raised[0], StopIteration)
+
+
+def test_int64_as_int_override() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud", int64_as_int=True)
+    assert client.subscribe("users:list", timeout=5).int64_as_int
+    subscription = client.subscribe("users:list", timeout=5, int64_as_int=False)
+    assert not subscription.int64_as_int
+    assert client.watch_all().int64_as_int
+    assert not client.watch_all(int64_as_int=False).int64_as_int
+    client.close()
+
+    wrapper = ConvexClient("https://made-up-animal.convex.cloud", timeout=5)
+    assert not wrapper.subscribe("users:list").inner.int64_as_int
+    assert wrapper.subscribe("users:list", int64_as_int=True).inner.int64_as_int
+    assert not wrapper.watch_all().inner.int64_as_int
+    assert wrapper.watch_all(int64_as_int=True).inner.int64_as_int
+    wrapper.close()
+
+
+@pytest.mark.parametrize(
+    "offload_nested_calls, expected",
+    [(False, RuntimeError), (True, ConvexTimeoutError)],
+)
+def test_nested_blocking_call(offload_nested_calls: bool, expected: type) -> None:
+    client = PyConvexClient(
+        "https://made-up-animal.convex.cloud",
+        offload_nested_calls=offload_nested_calls,
+    )
+    raised: List[type] = []
+    called = threading.Event()
+
+    def on_change(_state: object) -> None:
+        # state change callbacks run on a blocking thread of the client's runtime
+        try:
+            client.query("users:list", timeout=0.1)
+        except Exception as e:
+            raised.append(type(e))
+        called.set()
+
+    client.on_auth_state_change(on_change)
+    client.set_admin_auth("prod:made-up-animal|key")
+    assert called.wait(timeout=10)
+    assert raised[0] is expected
+
+
+def test_invalid_args() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    when = datetime.datetime.now()
+    with pytest.raises(TypeError, match="args.when: .* of type datetime"):
+        client.query("events:list", {"when": when})
+    with pytest.raises(TypeError, match="args.when: .* of type datetime"):
+        client.subscribe("events:list", {"when": when})
+    with pytest.raises(TypeError, match="names must be str"):
+        client.mutation("events:add", {1: "one"})  # type: ignore
+
+
+def test_conversion_error_path() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    args = {"items": [{"price": 1.0}] * 3 + [{"price": object()}]}
+    with pytest.raises(ConvexConversionError) as e:
+        client.mutation("orders:add", args)
+    assert e.value.path == "args.items[3].price"
+
+
+def test_malformed_auth() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    with pytest.raises(ValueError):
+        client.set_auth("not-a-jwt")
+    with pytest.raises(ValueError):
+        client.set_admin_auth("")
+    with pytest.raises(TypeError):
+        client.set_admin_auth("prod:made-up-animal|key", acting_as={"subject": 1})
+
+
+def test_acting_as() -> None:
+    acting_as = {
+        "issuer": "https://auth.example.com",
+        "subject": "user123",
+        "emailVerified": True,
+        "role": "admin",
+        "orgs": ["a", "b"],
+        "seats": 3,
+    }
+    identity = py_to_identity_to_py(acting_as)
+    assert identity == {
+        "tokenIdentifier": "https://auth.example.com|user123",
+        "issuer": "https://auth.example.com",
+        "subject": "user123",
+        "emailVerified": True,
+        "role": "admin",
+        "orgs": '["a", "b"]',
+        "seats": "3",
+    }
+    identity = py_to_identity_to_py({**acting_as, "tokenIdentifier": "custom"})
+    assert identity["tokenIdentifier"] == "custom"
+
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    client.set_admin_auth("prod:made-up-animal|key", acting_as=acting_as)
+
+
+def make_token(expires_at: float) -> str:
+    def segment(data: dict) -> str:
+        encoded = base64.urlsafe_b64encode(json.dumps(data).encode())
+        return encoded.rstrip(b"=").decode()
+
+    return f"{segment({'alg': 'RS256'})}.{segment({'exp': int(expires_at)})}.sig"
+
+
+def test_set_auth_callback() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    calls: List[bool] = []
+    refreshed = threading.Event()
+
+    def fetch_token(force_refresh_token: bool) -> str:
+        calls.append(force_refresh_token)
+        if len(calls) == 1:
+            # Close enough to expiry to be refreshed as soon as allowed.
+            return make_token(time.time() + 12)
+        refreshed.set()
+        return make_token(time.time() + 3600)
+
+    client.set_auth_callback(fetch_token)
+    assert calls == [False]
+    assert client.auth_state.status == "pending"
+    assert refreshed.wait(timeout=10)
+    assert calls[1] is True
+
+    def failing_fetch_token(force_refresh_token: bool) -> str:
+        raise KeyError("no token")
+
+    with pytest.raises(KeyError):
+        client.set_auth_callback(failing_fetch_token)
+    with pytest.raises(ValueError):
+        client.set_auth_callback(lambda force_refresh_token: "not-a-jwt")
+    client.close()
+
+
+def test_malformed_deployment_url() -> None:
+    with pytest.raises(ValueError, match="https://made-up-animal.convex.cloud"):
+        PyConvexClient("made-up-animal.convex.cloud")
+    with pytest.raises(ValueError, match="convex.site"):
+        PyConvexClient("https://made-up-animal.convex.site")
+    with pytest.raises(ValueError, match="path"):
+        PyConvexClient("https://made-up-animal.convex.cloud/api")
+    PyConvexClient("https://made-up-animal.convex.cloud/")
+
+
+@pytest.fixture
+def convex_env(monkeypatch: pytest.MonkeyPatch) -> None:
+    for var in ["CONVEX_URL", "CONVEX_DEPLOYMENT", "CONVEX_DEPLOY_KEY"]:
+        monkeypatch.delenv(var, raising=False)
+
+
+@pytest.mark.usefixtures("convex_env")
+def test_from_env(tmp_path: Path, monkeypatch: pytest.MonkeyPatch) -> None:
+    env_file = tmp_path / ".env.local"
+    with pytest.raises(ValueError, match="CONVEX_URL or CONVEX_DEPLOYMENT"):
+        PyConvexClient.from_env(env_file)
+
+    env_file.write_text(
+        "# Deployment used by `npx convex dev`\n"
+        "CONVEX_DEPLOYMENT=dev:made-up-animal # team: made-up, project: animal\n"
+    )
+    client = PyConvexClient.from_env(env_file)
+    assert client.auth_state.status == "cleared"
+    with pytest.raises(ValueError, match="CONVEX_DEPLOY_KEY"):
+        PyConvexClient.from_env(env_file, require_admin_key=True)
+
+    monkeypatch.setenv("CONVEX_DEPLOY_KEY", "prod:made-up-animal|key")
+    client = PyConvexClient.from_env(env_file, require_admin_key=True)
+    assert client.auth_state.status != "cleared"
+
+    monkeypatch.setenv("CONVEX_DEPLOYMENT", "dev:Not A Deployment")
+    monkeypatch.delenv("CONVEX_DEPLOY_KEY")
+    with pytest.raises(ValueError, match="CONVEX_DEPLOYMENT set in the environment"):
+        PyConvexClient.from_env(env_file)
+
+
+def test_auth_state() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    assert client.auth_state.status == "cleared"
+    client.set_auth(make_token(time.time() + 3600))
+    assert client.auth_state.status == "pending"
+    # malformed auth isn't set, so the previous auth stays active
+    with pytest.raises(ValueError):
+        client.set_auth("not-a-jwt")
+    assert client.auth_state.status == "pending"
+    # failing calls don't show that the deployment rejected the auth
+    with pytest.raises(ConvexTimeoutError):
+        client.query("users:list", timeout=0.1)
+    assert client.auth_state.status == "pending"
+    client.set_auth(None)
+    assert client.auth_state.status == "cleared"
+
+
+def test_on_auth_state_change() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    states: List[str] = []
+    changed = {status: threading.Event() for status in ["pending", "cleared"]}
+
+    def on_change(state: PyAuthState) -> None:
+        states.append(state.status)
+        changed[state.status].set()
+
+    client.on_auth_state_change(on_change)
+    client.set_auth(make_token(time.time() + 3600))
+    assert changed["pending"].wait(timeout=10)
+    with pytest.raises(ValueError):
+        client.set_auth("not-a-jwt")
+    client.set_auth(None)
+    assert changed["cleared"].wait(timeout=10)
+    assert states == ["pending", "cleared"]
+
+
+@pytest.mark.skipif(not hasattr(os, "fork"), reason="requires os.fork()")
+def test_fork() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    pid = os.fork()
+    if pid == 0:
+        try:
+            client.query("users:list")
+        except RuntimeError:
+            os._exit(0)
+        os._exit(1)
+    _, status = os.waitpid(pid, 0)
+    assert os.waitstatus_to_exitcode(status) == 0
+
+
+@pytest.mark.skipif(not hasattr(os, "fork"), reason="requires os.fork()")
+def test_fork_after_awaitable() -> None:
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+
+    async def main() -> None:
+        with pytest.raises(ConvexTimeoutError):
+            await client.aquery("users:list", timeout=0.1)
+
+    # starts the shared runtime, whose workers don't survive the fork
+    asyncio.run(main())
+    pid = os.fork()
+    if pid == 0:
+        try:
+            child = PyConvexClient("https://made-up-animal.convex.cloud")
+            with pytest.raises(RuntimeError, match="forked"):
+                child.aquery("users:list")
+            with pytest.raises(RuntimeError, match="forked"):
+                PyConvexClient(
+                    "https://made-up-animal.convex.cloud", shared_runtime=True
+                )
+        except BaseException:
+            os._exit(1)
+        os._exit(0)
+    _, status = os.waitpid(pid, 0)
+    assert os.waitstatus_to_exitcode(status) == 0
+
+
+@pytest.mark.skipif(not hasattr(signal, "setitimer"), reason="requires setitimer()")
+def test_signal_interrupts_call() -> None:
+    class Interrupted(Exception):
+        pass
+
+    def handler(_signum: int, _frame: object) -> None:
+        raise Interrupted()
+
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    with pytest.raises(ValueError):
+        set_signal_check_interval(0)
+    set_signal_check_interval(0.05)
+    previous = signal.signal(signal.SIGALRM, handler)
+    try:
+        start = time.monotonic()
+        signal.setitimer(signal.ITIMER_REAL, 0.2)
+        with pytest.raises(Interrupted):
+            client.query("users:list")
+        assert time.monotonic() - start < 0.5
+    finally:
+        signal.setitimer(signal.ITIMER_REAL, 0)
+        signal.signal(signal.SIGALRM, previous)
+        set_signal_check_interval(1)
+
+
+@pytest.mark.skipif(not hasattr(signal, "setitimer"), reason="requires setitimer()")
+def test_signal_wakes_up_call() -> None:
+    class Interrupted(Exception):
+        pass
+
+    def handler(_signum: int, _frame: object) -> None:
+        raise Interrupted()
+
+    client = PyConvexClient("https://made-up-animal.convex.cloud")
+    previous = signal.signal(signal.SIGALRM, handler)
+    try:
+        # signals wake the call up well before the next check a second later
+        start = time.monotonic()
+        signal.setitimer(signal.ITIMER_REAL, 0.2)
+        with pytest.raises(Interrupted):
+            client.mutation("users:add")
+        assert time.monotonic() - start < 0.8
+    finally:
+        signal.setitimer(signal.ITIMER_REAL, 0)
+        signal.signal(signal.SIGALRM, previous)
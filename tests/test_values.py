@@ -1,5 +1,6 @@
 import collections
 import json
+import types
 from typing import Any, Optional
 
 import _convex
@@ -28,6 +29,11 @@ def coerced_roundtrip(original: CoercibleToConvexValue) -> None:
     coerced = json_to_convex(json.loads(json.dumps(convex_to_json(original))))
     strict_roundtrip(coerced)
 
+    # Rust coerces values the same way
+    coerced_by_rust = _convex.py_to_rust_to_py(original)
+    assert coerced_by_rust == coerced
+    assert type(coerced_by_rust) is type(coerced)
+
 
 def strict_roundtrip(original: ConvexValue) -> None:
     """Assert that a Python value roundtrips to Convex types.
@@ -69,6 +75,11 @@ def coerced_roundtrip_raises(
         convex_to_json(original)
     if message:
         assert message in e.value.args[0]
+
+    with pytest.raises(_convex.ConvexConversionError) as rust_e:
+        _convex.py_to_rust_to_py(original)
+    if message:
+        assert message in rust_e.value.args[0]
     return e
 
 
@@ -144,7 +155,9 @@ def test_coercion() -> None:
     coerced_roundtrip((1, 2))
     coerced_roundtrip(range(10))
     coerced_roundtrip(bytearray(b"abc"))
+    coerced_roundtrip(memoryview(b"abc"))
     coerced_roundtrip(collections.Counter("asdf"))
+    coerced_roundtrip(types.MappingProxyType({"a": (1, 2.5)}))
 
 
 def test_non_values() -> None:
@@ -166,6 +179,7 @@ def test_non_values() -> None:
 def test_context_errors() -> None:
     coerced_roundtrip_raises({"$a": 1}, "starts with a '$'")
     coerced_roundtrip_raises({"b": {2: 1}}, "must be strings")
+    coerced_roundtrip_raises({"é": 1}, "invalid character")
 
 
 def test_decode_json() -> None:
@@ -173,3 +187,35 @@ def test_decode_json() -> None:
     with pytest.raises(ValueError) as e:
         json_to_convex({"$a": 1})
     assert "$" in e.value.args[0]
+
+
+def test_conversion_error_path() -> None:
+    with pytest.raises(_convex.ConvexConversionError) as e:
+        _convex.py_to_rust_to_py({"a": [None, {"b c": object()}]})
+    assert e.value.path == 'value.a[1]["b c"]'
+    assert "of type object" in e.value.args[0]
+
+    with pytest.raises(_convex.ConvexConversionError) as e:
+        _convex.py_to_rust_to_py([{1: 2}])
+    assert e.value.path == "value[0]"
+
+
+def test_int_policy() -> None:
+    big = 2**53 + 1
+    with pytest.raises(_convex.ConvexConversionError, match="int64"):
+        _convex.py_to_rust_to_py(big)
+    assert _convex.py_to_rust_to_py(big, "float") == float(big)
+    assert _convex.py_to_rust_to_py([big], "int64") == [ConvexInt64(big)]
+    assert type(_convex.py_to_rust_to_py(1, "int64")) is float
+    with pytest.raises(_convex.ConvexConversionError, match="Int64"):
+        _convex.py_to_rust_to_py(2**63, "int64")
+    with pytest.raises(ValueError, match="int_policy"):
+        _convex.py_to_rust_to_py(1, "exact")
+
+
+def test_int64_as_int() -> None:
+    original = {"id": ConvexInt64(2**60), "ids": [ConvexInt64(-1)]}
+    decoded = _convex.py_to_rust_to_py(original, int64_as_int=True)
+    assert decoded == {"id": 2**60, "ids": [-1]}
+    assert type(decoded["id"]) is int
+    assert type(_convex.py_to_rust_to_py(original)["id"]) is ConvexInt64